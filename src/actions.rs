@@ -1,43 +1,141 @@
 use bevy::prelude::{Color, Event, EventWriter};
-use serde::{Deserialize, Serialize};
-use serde_json::Error;
-use serde::de::Error as de_Error;
-use crate::{actions, Point};
+use schemars::{schema_for, JsonSchema};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use crate::grid::{to_hex, GridState, MAX_GRID_SIZE};
+use crate::rules::MIN_TICK_MS;
+use crate::sound::{MAX_DURATION_MS, MAX_FREQUENCY, MIN_DURATION_MS, MIN_FREQUENCY};
+use crate::streaming::Drawn;
+use crate::validation::{PointValidator, Violation};
+use crate::Point;
 
-pub enum ActionTypes {
-    UpdateGame,
-    Sorry,
-}
-
-#[derive(Event)]
+/// Serialized for clients watching the game, e.g. `{"type":"sorry","error":"..."}`
+#[derive(Event, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum SceneUpdate {
     UpdateGame {
         clear_grid: Option<bool>,
         update_points: Vec<PointColor>,
         game_end: Option<bool>,
         message: Option<String>,
+        score: Option<i64>,
     },
+    SetGridSize { width: u8, height: u8 },
+    SetRules { script: String, tick_ms: u32 },
+    DeclareActions { actions: Vec<CustomAction> },
+    PlaySound(PlaySound),
     Sorry { error: String },
 }
 
+/// Everything the game master can respond with. The system prompt embeds the schema
+/// generated from these types, so the prompt and the parser can't disagree.
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "action", content = "value")]
+pub(crate) enum Action {
+    /// Update the board after the player moves, or set up a new game.
+    UpdateGame(UpdateGame),
+    /// Resize the board to suit the game, e.g. 10x10 for tic-tac-toe or 40x30 for breakout. This clears it.
+    SetGridSize(SetGridSize),
+    /// Run a real-time game like snake or breakout locally. The script runs on a timer and gets the player's
+    /// moves instead of you, until it ends the game, fails, or you send an empty script.
+    SetRules(SetRules),
+    /// Give the game controls beyond the arrow keys, like "fire" or "jump". Replaces any declared before.
+    DeclareActions(DeclareActions),
+    /// Play a sound effect, synthesized on the player's machine. To play one along with a board update,
+    /// use the `sounds` of UpdateGame instead.
+    PlaySound(PlaySound),
+    /// Anything that isn't a game move.
+    Sorry(Sorry),
+}
 
-#[derive(Deserialize, Serialize)]
-pub(crate) struct Sorry {
-    pub(crate) error: String,
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct SetGridSize {
+    #[schemars(range(min = 1, max = "MAX_GRID_SIZE"))]
+    width: u8,
+    #[schemars(range(min = 1, max = "MAX_GRID_SIZE"))]
+    height: u8,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct SetRules {
+    /// Rhai source. `fn init()` draws the starting board and returns the game state as a map.
+    /// `fn tick()` runs every tick, `fn on_input(action)` on every move ("left", "right", "up", "down",
+    /// "action", "confirm", "cancel" or a declared action's name) and `fn on_click(x, y)` on every click;
+    /// all of them see the state as `this`. Built in: `set(x, y, hex)`, `get(x, y)` (the cell's hex), `clear()`,
+    /// `width()`, `height()`, `message(text)` to narrate, `score(points)`, `sound(waveform, frequency, duration_ms)`,
+    /// `game_over(won)` and `ask(text)` to hand a turn to you.
+    script: String,
+    /// Milliseconds between ticks, e.g. 150 for snake.
+    #[schemars(range(min = "MIN_TICK_MS"))]
+    tick_ms: u32,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct DeclareActions {
+    actions: Vec<CustomAction>,
 }
 
-impl StringDefinition for Sorry {
-    fn string_definition() -> String {
-        serde_json::to_string(&Sorry {
-            error: "String".to_string(),
-        }).unwrap()
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+pub struct CustomAction {
+    /// Sent back to you as the `move_action`, e.g. "fire".
+    pub(crate) name: String,
+    /// Suggested key, named like "KeyF", "Space" or "Digit1".
+    pub(crate) key: Option<String>,
+    /// What it does, shown to the player.
+    pub(crate) description: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+pub struct PlaySound {
+    pub(crate) waveform: Waveform,
+    /// Pitch in Hz, e.g. 880 for a pickup beep or 110 for a thud. Ignored for noise.
+    #[schemars(range(min = "MIN_FREQUENCY", max = "MAX_FREQUENCY"))]
+    pub(crate) frequency: f32,
+    #[schemars(range(min = "MIN_DURATION_MS", max = "MAX_DURATION_MS"))]
+    pub(crate) duration_ms: u32,
+    pub(crate) envelope: Option<Envelope>,
+    /// From 0 to 1, 0.5 if left out.
+    pub(crate) volume: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    Square,
+    Triangle,
+    Sawtooth,
+    Noise,
+}
+
+/// How loud the sound is over time: it rises over `attack_ms`, falls to `sustain` over `decay_ms`,
+/// and fades out over the last `release_ms`.
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+#[serde(default)]
+pub struct Envelope {
+    pub(crate) attack_ms: u32,
+    pub(crate) decay_ms: u32,
+    /// From 0 to 1.
+    pub(crate) sustain: f32,
+    pub(crate) release_ms: u32,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self { attack_ms: 5, decay_ms: 50, sustain: 0.7, release_ms: 50 }
     }
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct Sorry {
+    pub(crate) error: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub struct PointHex {
-    hex: String,
-    point: Point,
+    /// Color of the cell, e.g. "#ff0000".
+    pub(crate) hex: String,
+    pub(crate) point: Point,
 }
 
 pub struct PointColor {
@@ -45,131 +143,278 @@ pub struct PointColor {
     pub(crate) point: Point,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Written back out the way the game master sends it
+impl Serialize for PointColor {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PointHex { hex: to_hex(self.color), point: self.point.clone() }.serialize(serializer)
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub(crate) struct UpdateGame {
+    /// Set every cell back to white before applying `update_points`.
     clear_grid: Option<bool>,
+    /// Only the cells that change.
     update_points: Vec<PointHex>,
+    /// `true` if the player won, `false` if they lost, left out while the game goes on.
     game_end: Option<bool>,
+    /// Narration for the player: instructions, commentary, the outcome.
     message: Option<String>,
+    /// The player's score, left out if it hasn't changed or the game doesn't keep score.
+    score: Option<i64>,
+    /// Sound effects to play with the update, e.g. when food is eaten or a wall is hit.
+    sounds: Option<Vec<PlaySound>>,
 }
 
-impl StringDefinition for UpdateGame {
-    // The JSON schema for UpdateGame
-    fn string_definition() -> String {
-        "{\"type\":\"object\",\"properties\":{\"Point\":{\"type\":\"object\",\"properties\":{\"x\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":20,\"exclusiveMaximum\":false},\"y\":{\"type\":\"integer\",\"minimum\":0,\"maximum\":20,\"exclusiveMaximum\":false}},\"required\":[\"x\",\"y\"]},\"PointHex\":{\"type\":\"object\",\"properties\":{\"hex\":{\"type\":\"string\"},\"point\":{\"$ref\":\"#/properties/Point\"}},\"required\":[\"hex\",\"point\"]},\"UpdateGame\":{\"type\":\"object\",\"properties\":{\"clear_grid\":{\"type\":[\"boolean\",\"null\"]},\"update_points\":{\"type\":\"array\",\"items\":{\"$ref\":\"#/properties/PointHex\"}},\"game_end\":{\"type\":[\"boolean\",\"null\"]},\"message\":{\"type\":[\"string\",\"null\"]}},\"required\":[\"update_points\"]}}}".to_string()
-    }
+/// What applying a response left for the rest of the turn to deal with
+#[derive(Default)]
+pub struct Applied {
+    /// Points that had to be fixed
+    pub violations: Vec<Violation>,
+    /// The board was resized and nothing was drawn on it afterwards
+    pub left_blank: bool,
 }
 
-trait StringDefinition {
-    fn string_definition() -> String;
-}
-
-pub fn handle_request(scene_update_events: &mut EventWriter<SceneUpdate>, request_json: &str) {
-    match handle_request_with_result(request_json) {
-        Ok(handled_action) => {
-            match handled_action.action {
-                ActionTypes::UpdateGame => {
-                    if let Ok(turn_circle_color) = serde_json::from_str::<actions::UpdateGame>(&handled_action.value) {
-                        scene_update_events.send(SceneUpdate::UpdateGame {
-                            clear_grid: turn_circle_color.clear_grid,
-                            update_points: turn_circle_color.update_points.iter().map(|point_color_raw| PointColor {
-                                color: Color::hex(&point_color_raw.hex).unwrap_or(Color::PINK),
-                                point: point_color_raw.point.clone(),
-                            }).collect(),
-                            game_end: turn_circle_color.game_end,
-                            message: turn_circle_color.message,
-                        });
-                    } else {
-                        println!("Error deserializing: {}", request_json);
-                    }
-                },
-                ActionTypes::Sorry => {
-                    let sorry: actions::Sorry = serde_json::from_str(&handled_action.value).unwrap();
-                    scene_update_events.send(SceneUpdate::Sorry {
-                        error: sorry.error,
-                    });
-                },
-            }
-        },
-        Err(e) => {
-            println!("Error deserializing: {} -- {}", e, request_json);
-            // Execute sorry action?
+impl Applied {
+    /// Applies the next action of the response
+    pub fn emit(
+        &mut self,
+        scene_update_events: &mut EventWriter<SceneUpdate>,
+        action: Action,
+        validator: &mut PointValidator,
+        drawn: Drawn,
+    ) {
+        match action {
+            Action::SetGridSize(_) => self.left_blank = true,
+            Action::UpdateGame(_) => self.left_blank = false,
+            _ => {}
         }
+        self.violations.extend(emit_action(scene_update_events, action, validator, drawn));
     }
 }
 
-fn handle_request_with_result(request_json: &str) -> Result<HandledAction, Error> {
-    let action: Action = serde_json::from_str(request_json)?;
-    let action_type = match action.action.as_str() {
-        "UpdateGame" => ActionTypes::UpdateGame,
-        "Sorry" => ActionTypes::Sorry,
-        _ => {
-            return Err(serde_json::Error::custom("Invalid action"));
+/// Parses, validates and applies a game master response
+pub fn handle_request(
+    scene_update_events: &mut EventWriter<SceneUpdate>,
+    request_json: &str,
+    validator: &mut PointValidator,
+    drawn: Drawn,
+) -> Result<Applied, String> {
+    let action = parse_request(request_json)?;
+    let mut applied = Applied::default();
+    applied.emit(scene_update_events, action, validator, drawn);
+    Ok(applied)
+}
+
+/// Checks an action against the board and sends what's left of it on as a scene update.
+/// Whatever was `drawn` while the response streamed in is only checked, not sent again,
+/// and a resize applies to the validator right away so later actions are checked against the new size.
+pub fn emit_action(
+    scene_update_events: &mut EventWriter<SceneUpdate>,
+    action: Action,
+    validator: &mut PointValidator,
+    drawn: Drawn,
+) -> Vec<Violation> {
+    let (scene_update, violations) = match action {
+        Action::UpdateGame(update_game) => {
+            let (already_drawn, remaining) = update_game.update_points.split_at(drawn.points.min(update_game.update_points.len()));
+            let (_, mut violations) = validator.validate(already_drawn);
+            let (update_points, remaining_violations) = validator.validate(remaining);
+            violations.extend(remaining_violations);
+            for sound in update_game.sounds.into_iter().flatten() {
+                scene_update_events.send(SceneUpdate::PlaySound(sound));
+            }
+            let scene_update = SceneUpdate::UpdateGame {
+                clear_grid: update_game.clear_grid.filter(|_| !drawn.cleared),
+                update_points,
+                game_end: update_game.game_end,
+                message: update_game.message,
+                score: update_game.score,
+            };
+            (scene_update, violations)
         },
-    };
-    Ok(
-        HandledAction {
-            action: action_type,
-            value: action.value,
+        Action::SetGridSize(size) => {
+            let violation = validator.resize(size.width, size.height);
+            let (width, height) = validator.size();
+            (SceneUpdate::SetGridSize { width, height }, violation.into_iter().collect())
         }
-    )
+        Action::SetRules(rules) => (SceneUpdate::SetRules { script: rules.script, tick_ms: rules.tick_ms }, vec![]),
+        Action::DeclareActions(declared) => (SceneUpdate::DeclareActions { actions: declared.actions }, vec![]),
+        Action::PlaySound(sound) => (SceneUpdate::PlaySound(sound), vec![]),
+        Action::Sorry(sorry) => (SceneUpdate::Sorry { error: sorry.error }, vec![]),
+    };
+    scene_update_events.send(scene_update);
+    violations
 }
 
-pub fn build_system_prompt() -> String {
-    let actions = [
-        Action {
-            action: "UpdateGame".to_string(),
-            value: UpdateGame::string_definition(),
-        },
-        Action {
-            action: "Sorry".to_string(),
-            value: Sorry::string_definition(),
+/// Reads a game master response, describing what was wrong with it if it can't
+pub fn parse_request(request_json: &str) -> Result<Action, String> {
+    let action = serde_json::from_str::<Value>(request_json)
+        .map_err(|e| format!("The response is not valid JSON: {}", e))?;
+    parse_action(action)
+}
+
+fn parse_action(action: Value) -> Result<Action, String> {
+    serde_json::from_value::<Action>(action)
+        .map_err(|e| format!("The response does not follow the action schema: {}", e))
+}
+
+/// The schema for `Action`, with point coordinates bounded by the current board
+fn action_schema(grid: &GridState) -> Value {
+    let mut schema = serde_json::to_value(schema_for!(Action)).unwrap();
+    let point = &mut schema["definitions"]["Point"]["properties"];
+    point["x"]["maximum"] = json!(grid.width - 1);
+    point["y"]["maximum"] = json!(grid.height - 1);
+    schema
+}
+
+/// Each `Action` as an OpenAI tool definition, named in snake case (`UpdateGame` becomes `update_game`)
+pub fn action_tools(grid: &GridState) -> Vec<Value> {
+    let schema = action_schema(grid);
+    let definitions = &schema["definitions"];
+
+    schema["oneOf"].as_array().into_iter().flatten().filter_map(|variant| {
+        let action = variant["properties"]["action"]["enum"][0].as_str()?;
+        let reference = variant["properties"]["value"]["$ref"].as_str()?;
+        let mut parameters = definitions[reference.trim_start_matches("#/definitions/")].clone();
+        parameters["definitions"] = definitions.clone();
+
+        Some(json!({
+            "type": "function",
+            "function": {
+                "name": tool_name(action),
+                "description": variant["description"],
+                "parameters": parameters,
+            }
+        }))
+    }).collect()
+}
+
+/// Reads a tool call from the game master as the action it stands for
+pub fn parse_tool_call(name: &str, arguments: &str) -> Result<Action, String> {
+    let action = name.split('_')
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars.next().map(|first| first.to_ascii_uppercase()).into_iter().chain(chars)
+        })
+        .collect::<String>();
+    let value = serde_json::from_str::<Value>(arguments)
+        .map_err(|e| format!("The arguments to {} are not valid JSON: {}", name, e))?;
+
+    parse_action(json!({ "action": action, "value": value }))
+        .map_err(|e| format!("Calling {} with {} failed. {}", name, arguments, e))
+}
+
+fn tool_name(action: &str) -> String {
+    action.chars().enumerate().fold(String::new(), |mut name, (i, c)| {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
         }
-    ];
-    let serialized_actions = serde_json::to_string(&actions).unwrap();
+        name.push(c.to_ascii_lowercase());
+        name
+    })
+}
+
+pub fn build_system_prompt(tool_calling: bool, grid: &GridState) -> String {
+    let protocol = if tool_calling {
+        "Respond to every message by calling exactly one of your tools. \
+        If you are updating the game board, YOU MUST FOLLOW THE TOOL'S SCHEMA.".to_string()
+    } else {
+        let schema = action_schema(grid).to_string();
+        format!(
+            "Always respond with valid JSON. Every response is exactly one action matching this JSON schema: {}. \
+            If you are updating the game board, YOU MUST FOLLOW THE JSON SCHEMA. \
+            Always fill in `action` with the name of the action and `value` with a JSON object for it, not a string.",
+            schema
+        )
+    };
     format!(
-        "Always respond with valid JSON. You have access to the following possible actions: {}. \
-        If you are updating the game board, YOU MUST FOLLOW THE JSON SCHEMA. \
+        "{} \
         Every point requires an x and y value, and every hex requires a hex value. \
         This is how you make the game possible to play - you update the board after the user takes an action, \
         and you provide the user with the next state of the board. \
         You are a game master and you get to decide on a game to play, the rules, and the outcome. \
-        You facilitate every interaction by updating a 20x20 square grid. You can make any rules you want, \
-        like snake, or breakout or anything else, as long as you follow the JSON schema to represent the board. \
-        Do not make up an ascii representation of the board or choose a game that can't be played with keyboard. \
+        You facilitate every interaction by updating a grid that is currently {}x{} (width x height). \
+        Pick the size that suits your game with SetGridSize before drawing it. You can make any rules you want, \
+        like snake, or breakout or anything else, as long as you follow the schema to represent the board. \
+        For real-time games, send the rules once with SetRules and let them run; you will hear from the game \
+        again when the script calls ask or fails. \
+        Bring the game to life with sound effects: short beeps for eating food, thuds for hitting walls, \
+        a rising tone for clearing a line. Add them to the `sounds` of an update, or use PlaySound on its own. \
+        Besides the arrow keys the player has \"action\", \"confirm\" and \"cancel\"; if your game needs other \
+        controls, declare them with DeclareActions when it starts. \
+        The player can also click a cell, which reaches you as a `click_cell` point, so board games work too. \
+        Do not make up an ascii representation of the board or choose a game that can't be played with keyboard or mouse. \
         Always start the player somewhere. There must be one non-white square when the game starts! \
         It is critical that when a new game is being started you provide detailed instructions on how, the \
         game is played. A new game can start either because it's the first message, \
         or because the game has ended, or you, or the player wants to start over, \
-        The user will respond to you with a movement action. \
-        Always respond with valid JSON. Only respond with valid actions. \
+        The user will respond to you with a movement action, followed by the current board: \
+        its size and every non-white cell grouped by color. Trust that board over your memory of it. \
         If the user is attempting a movement action, update the board, and appropriately \
-        fill in the values as outlined. Always fill in `action` with the appropriate \
-        string and `value` with the appropriate stringified and escaped JSON. \
-        It is critical the JSON is escaped. Otherwise, choose the Sorry action. \
+        fill in the values as outlined. Otherwise, choose the Sorry action. \
         Any other request should use the Sorry action and place \"sorry\" in its `error`.",
-        serialized_actions
+        protocol, grid.width, grid.height
     )
 }
 
-#[derive(Deserialize, Serialize)]
-pub(crate) struct Action {
-    pub(crate) action: String,
-    pub(crate) value: String,
-}
-
-struct HandledAction {
-    action: ActionTypes,
-    value: String,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_scene_updates_serialize_with_hex_colors() {
+        let update = SceneUpdate::UpdateGame {
+            clear_grid: Some(true),
+            update_points: vec![PointColor { color: Color::rgb(1.0, 0.0, 0.0), point: Point { x: 1, y: 2 } }],
+            game_end: None,
+            message: Some("Go".to_string()),
+            score: None,
+        };
+        let json = serde_json::to_value(&update).unwrap();
+        assert_eq!(json["type"], "update_game");
+        assert_eq!(json["update_points"], json!([{ "hex": "#ff0000", "point": { "x": 1, "y": 2 } }]));
+        assert_eq!(json["message"], "Go");
+
+        let sorry = serde_json::to_value(SceneUpdate::Sorry { error: "Nope".to_string() }).unwrap();
+        assert_eq!(sorry, json!({ "type": "sorry", "error": "Nope" }));
+    }
+
     #[test]
     fn test_build_system_prompt() {
-        let prompt = build_system_prompt();
-        assert_eq!(prompt, "Always respond with valid JSON. Given the following possible actions: [{\"action\":\"TurnCircleColor\",\"value\":\"{\\\"hex\\\":\\\"String\\\"}\"},{\"action\":\"Sorry\",\"value\":\"{\\\"error\\\":\\\"String\\\"}\"}], choose the one that best represents the user's request, and appropriately fill in the values as outlined. If none match, choose the Sorry action.");
+        let grid = GridState::new(10, 8);
+        let prompt = build_system_prompt(false, &grid);
+        let schema = action_schema(&grid);
+        assert!(prompt.contains(&schema.to_string()));
+        assert!(prompt.contains("10x8"));
+        assert!(!build_system_prompt(true, &grid).contains(&schema.to_string()));
+        assert_eq!(schema["definitions"]["Point"]["properties"]["x"]["maximum"], 9);
+        assert_eq!(schema["definitions"]["Point"]["properties"]["y"]["maximum"], 7);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_action_tools_round_trip() {
+        let tools = action_tools(&GridState::new(20, 20));
+        let names: Vec<_> = tools.iter().map(|tool| tool["function"]["name"].as_str().unwrap()).collect();
+        assert_eq!(names, ["update_game", "set_grid_size", "set_rules", "declare_actions", "play_sound", "sorry"]);
+        assert!(tools[0]["function"]["parameters"]["properties"]["update_points"].is_object());
+
+        let update = parse_tool_call("update_game", r##"{"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}}]}"##);
+        assert!(matches!(update, Ok(Action::UpdateGame(update_game)) if update_game.update_points.len() == 1));
+        assert!(parse_tool_call("fly_away", "{}").is_err());
+    }
+
+    #[test]
+    fn test_parse_request_reads_nested_value() {
+        let update = parse_request(r##"{"action":"UpdateGame","value":{"clear_grid":true,"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}}]}}"##);
+        assert!(matches!(update, Ok(Action::UpdateGame(update_game)) if update_game.clear_grid == Some(true) && update_game.update_points.len() == 1));
+    }
+
+    #[test]
+    fn test_parse_request_explains_invalid_value() {
+        let error = parse_request(r##"{"action":"UpdateGame","value":{"update_points":[{"hex":"#fff"}]}}"##)
+            .err()
+            .unwrap();
+        assert!(error.contains("point"), "{}", error);
+    }
+}
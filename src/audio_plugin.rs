@@ -1,61 +1,264 @@
 use bevy::prelude::*;
-use reqwest::Client;
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Source};
-use std::env;
+use crossbeam_channel::{Receiver, Sender};
+use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
 use std::io::Cursor;
-use std::process::Command;
-use tokio::runtime::Runtime;
+use std::sync::{Arc, Mutex};
+use std::{env, fs, thread};
+use crate::input::InputBindings;
+use crate::network::ChatInputRequest;
+use crate::tts::{tts_from_env, TtsProvider};
+use crate::EguiWantsFocus;
+
+pub const WIN_LINE: &str = "Everyone wins sometimes.";
+pub const LOSE_LINE: &str = "Game over man, game over.";
+pub const LOST_TRACK_LINE: &str = "Sorry, I lost track of the game. Please try that again.";
 
 #[derive(Event)]
 pub struct RequestAudioEvent {
     pub text: String,
+    pub priority: NarrationPriority,
+}
+
+/// Higher priority lines are read first, and `High` ones cut off whatever is playing
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum NarrationPriority {
+    Low,
+    Normal,
+    /// Also survives the start of a new turn
+    High,
+}
+
+/// Reads narration out loud
+pub struct NarrationPlugin;
+
+impl Plugin for NarrationPlugin {
+    fn build(&self, app: &mut App) {
+        let (provider, cached) = tts_from_env();
+        let narrator = Narrator::spawn(provider);
+        // Without a cache to keep them in, prewarmed lines would be paid for and thrown away
+        if cached {
+            narrator.prewarm(stock_lines());
+        }
+
+        app.add_event::<RequestAudioEvent>()
+            .insert_resource(narrator)
+            .init_resource::<NarrationQueue>()
+            .add_systems(Update, request_audio_system)
+            .add_systems(Update, play_narration.after(request_audio_system))
+            .add_systems(Update, skip_narration.run_if(resource_equals(EguiWantsFocus(false))));
+    }
+}
+
+/// Fetches, decodes and plays speech on a worker thread, one line at a time, so the frame never waits on it
+#[derive(Resource)]
+pub struct Narrator {
+    lines: Sender<String>,
+    // Kept apart from `lines` so caching never holds up a line that's waiting to be spoken
+    prewarm: Sender<String>,
+    // One message per spoken line, once it has finished or failed
+    finished: Receiver<()>,
+    // The line playing right now, so it can be cut short
+    playing: Arc<Mutex<Option<Arc<Sink>>>>,
+}
+
+enum NarrationJob {
+    Speak(String),
+    /// Synthesize without playing, so the speech cache has it ready
+    Prewarm(String),
+}
+
+impl NarrationJob {
+    /// Waits for the next job, taking lines to speak before lines to prewarm. `None` once the narrator is gone.
+    fn next(lines: &Receiver<String>, prewarm: &Receiver<String>) -> Option<Self> {
+        if let Ok(text) = lines.try_recv() {
+            return Some(Self::Speak(text));
+        }
+        crossbeam_channel::select! {
+            recv(lines) -> text => text.ok().map(Self::Speak),
+            recv(prewarm) -> text => text.ok().map(Self::Prewarm),
+        }
+    }
+}
+
+impl Narrator {
+    pub fn spawn(provider: Box<dyn TtsProvider>) -> Self {
+        let (lines, line_receiver) = crossbeam_channel::unbounded::<String>();
+        let (prewarm, prewarm_receiver) = crossbeam_channel::unbounded::<String>();
+        let (finished_sender, finished) = crossbeam_channel::unbounded();
+        let playing = Arc::new(Mutex::new(None));
+        let worker_playing = playing.clone();
+
+        thread::spawn(move || {
+            // The output stream has to stay on the thread that opened it
+            let (_stream, handle) = match OutputStream::try_default() {
+                Ok(output) => output,
+                Err(e) => {
+                    println!("No audio output, narration is off: {}", e);
+                    return;
+                }
+            };
+
+            while let Some(job) = NarrationJob::next(&line_receiver, &prewarm_receiver) {
+                let result = match job {
+                    NarrationJob::Speak(text) => {
+                        let played = provider.synthesize(&text).and_then(|audio| match audio {
+                            Some(audio) => play(&handle, &worker_playing, audio),
+                            None => Ok(()),
+                        });
+                        let _ = finished_sender.send(());
+                        played
+                    }
+                    NarrationJob::Prewarm(text) => provider.synthesize(&text).map(|_| ()),
+                };
+                if let Err(e) = result {
+                    eprintln!("Error: {}", e);
+                }
+            }
+        });
+
+        Self { lines, prewarm, finished, playing }
+    }
+
+    /// Returns false if the worker has stopped, e.g. because there is no audio output
+    fn speak(&self, text: String) -> bool {
+        self.lines.send(text).is_ok()
+    }
+
+    /// Stops the line that is playing, if any
+    pub fn skip(&self) {
+        if let Some(sink) = self.playing.lock().unwrap().as_ref() {
+            sink.stop();
+        }
+    }
+
+    pub fn prewarm(&self, texts: impl IntoIterator<Item = String>) {
+        for text in texts {
+            let _ = self.prewarm.send(text);
+        }
+    }
+}
+
+/// Lines worth having in the speech cache before they're needed: the built in ones,
+/// plus one per line of the file at `GM_TTS_PREWARM`
+fn stock_lines() -> Vec<String> {
+    let mut lines: Vec<String> = [WIN_LINE, LOSE_LINE, LOST_TRACK_LINE].map(String::from).into();
+    if let Ok(path) = env::var("GM_TTS_PREWARM") {
+        match fs::read_to_string(&path) {
+            Ok(file) => lines.extend(file.lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from)),
+            Err(e) => println!("Failed to read {}: {}", path, e),
+        }
+    }
+    lines
+}
+
+struct QueuedLine {
+    text: String,
+    priority: NarrationPriority,
+}
+
+/// Lines waiting to be read, and the one being read now
+#[derive(Resource, Default)]
+pub struct NarrationQueue {
+    lines: Vec<QueuedLine>,
+    speaking: Option<QueuedLine>,
+}
+
+impl NarrationQueue {
+    fn push(&mut self, text: String, priority: NarrationPriority) {
+        self.lines.push(QueuedLine { text, priority });
+    }
+
+    /// Whatever is left over from earlier turns is out of date, unless it's important
+    fn new_turn(&mut self) {
+        self.lines.retain(|line| line.priority == NarrationPriority::High);
+    }
+
+    /// The highest priority line, first come first served within a priority
+    fn next(&mut self) -> Option<QueuedLine> {
+        let index = (0..self.lines.len()).rev().max_by_key(|&i| self.lines[i].priority)?;
+        Some(self.lines.remove(index))
+    }
+
+    /// The line being read out, for a speaking indicator
+    pub fn speaking(&self) -> Option<&str> {
+        self.speaking.as_ref().map(|line| line.text.as_str())
+    }
 }
 
 pub fn request_audio_system(
     mut event_reader: EventReader<RequestAudioEvent>,
+    mut chat_input: EventReader<ChatInputRequest>,
+    mut queue: ResMut<NarrationQueue>,
+    narrator: Res<Narrator>,
 ) {
+    if chat_input.read().count() > 0 {
+        queue.new_turn();
+    }
+
     for event in event_reader.read() {
+        let interrupts = event.priority == NarrationPriority::High
+            && queue.speaking.as_ref().is_some_and(|line| line.priority < NarrationPriority::High);
+        if interrupts {
+            narrator.skip();
+        }
+        queue.push(event.text.clone(), event.priority);
+    }
+}
 
-        // Environment variable for API key
-        let api_key = env::var("ELEVEN_LABS_API_KEY").expect("ELEVEN_LABS_API_KEY must be set");
-        let voice_id = "ZiJr5cZOXQztQsR7bLrz";
-        let text = event.text.clone();
-        let model_id = "eleven_multilingual_v2";
-        let stability = 0.5;
-        let similarity_boost = 0.5;
-
-        // Construct the JSON data to send
-        let json_payload = format!(
-            r#"{{
-            "text": "{}",
-            "model_id": "{}",
-            "voice_settings": {{
-                "stability": {},
-                "similarity_boost": {}
-            }}
-        }}"#,
-            text, model_id, stability, similarity_boost
-        );
-
-        // Create the full curl command as a shell string
-        let command = format!(
-            "curl -X POST \"https://api.elevenlabs.io/v1/text-to-speech/{}\" \
-        -H \"Content-Type: application/json\" \
-        -H \"xi-api-key: {}\" \
-        -d '{}' -o temp_audio.mp3 && afplay temp_audio.mp3 && rm temp_audio.mp3",
-            voice_id, api_key, json_payload
-        );
-
-        // Execute the command in a shell
-        let output = Command::new("sh")
-            .arg("-c")
-            .arg(command)
-            .output()
-            .expect("Failed to execute shell command");
-
-        // Check for errors
-        if !output.status.success() {
-            eprintln!("Error: {}", String::from_utf8_lossy(&output.stderr));
+fn play_narration(mut queue: ResMut<NarrationQueue>, narrator: Res<Narrator>) {
+    if narrator.finished.try_iter().count() > 0 {
+        queue.speaking = None;
+    }
+    if queue.speaking.is_some() {
+        return;
+    }
+
+    if let Some(line) = queue.next() {
+        if narrator.speak(line.text.clone()) {
+            queue.speaking = Some(line);
         }
     }
 }
+
+fn skip_narration(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    narrator: Res<Narrator>,
+) {
+    if keyboard.just_pressed(bindings.skip_narration) {
+        narrator.skip();
+    }
+}
+
+/// Blocks the worker until the clip has finished or been skipped, so lines don't talk over each other
+fn play(handle: &OutputStreamHandle, playing: &Mutex<Option<Arc<Sink>>>, audio: Vec<u8>) -> Result<(), String> {
+    let sink = Arc::new(Sink::try_new(handle).map_err(|e| e.to_string())?);
+    let source = Decoder::new(Cursor::new(audio)).map_err(|e| format!("Failed to decode speech: {}", e))?;
+    sink.append(source);
+    *playing.lock().unwrap() = Some(sink.clone());
+    sink.sleep_until_end();
+    *playing.lock().unwrap() = None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_orders_by_priority_and_drops_stale_lines() {
+        let mut queue = NarrationQueue::default();
+        queue.push("first".to_string(), NarrationPriority::Normal);
+        queue.push("quiet".to_string(), NarrationPriority::Low);
+        queue.push("second".to_string(), NarrationPriority::Normal);
+        queue.push(LOSE_LINE.to_string(), NarrationPriority::High);
+        assert_eq!(queue.next().unwrap().text, LOSE_LINE);
+        assert_eq!(queue.next().unwrap().text, "first");
+
+        queue.push(WIN_LINE.to_string(), NarrationPriority::High);
+        queue.new_turn();
+        queue.push("fresh".to_string(), NarrationPriority::Normal);
+        let order: Vec<_> = std::iter::from_fn(|| queue.next()).map(|line| line.text).collect();
+        assert_eq!(order, [WIN_LINE, "fresh"]);
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+use std::{env, fs};
+use bevy::prelude::*;
+use bevy_http_client::HttpClient;
+use bevy_http_client::prelude::TypedRequest;
+use crate::network::{env_flag, AllMessages, ChatCompletionRequest, ChatCompletionResponse};
+
+/// Where an HTTP backend posts its completion requests.
+pub struct Endpoint<'a> {
+    pub url: &'a str,
+    pub api_key: Option<&'a str>,
+}
+
+/// How a backend answers a completion request.
+pub enum BackendReply {
+    /// Sent over HTTP, the answer arrives later as a `TypedResponse`.
+    Pending(TypedRequest<ChatCompletionResponse>),
+    /// Answered in-process, no network involved.
+    Ready(ChatCompletionResponse),
+}
+
+/// A chat completion provider the game master can talk to.
+///
+/// Every implementation speaks the OpenAI chat completions protocol, so the
+/// Bevy systems only ever build a `ChatCompletionRequest` and hand it over.
+pub trait GameMasterBackend: Send + Sync + 'static {
+    /// Human readable provider name, used for logging.
+    fn name(&self) -> &str;
+
+    /// Model sent with every completion request.
+    fn model(&self) -> &str;
+
+    /// The chat completions endpoint, or `None` for backends that answer in-process.
+    fn endpoint(&self) -> Option<Endpoint<'_>>;
+
+    /// Whether the backend understands the `tools` API. Backends that don't get JSON mode.
+    fn supports_tools(&self) -> bool {
+        true
+    }
+
+    /// Backends without an endpoint must override this.
+    fn request(&mut self, body: &ChatCompletionRequest) -> BackendReply {
+        let endpoint = self.endpoint().expect("backend has no endpoint and does not override request");
+        let authorization = endpoint.api_key.map(|key| format!("Bearer {}", key));
+        let mut headers = vec![("Content-Type", "application/json")];
+        if let Some(authorization) = &authorization {
+            headers.push(("Authorization", authorization));
+        }
+
+        BackendReply::Pending(
+            HttpClient::new()
+                .post(endpoint.url)
+                .headers(&headers)
+                .json(body)
+                .with_type::<ChatCompletionResponse>(),
+        )
+    }
+}
+
+/// The hosted Groq API.
+pub struct GroqBackend {
+    api_key: String,
+    model: String,
+}
+
+impl GroqBackend {
+    pub fn from_env() -> Self {
+        Self {
+            api_key: env::var("GROQ_API_KEY").expect("GROQ_API_KEY must be set"),
+            model: env::var("GM_MODEL").unwrap_or_else(|_| "llama3-70b-8192".to_string()),
+        }
+    }
+}
+
+impl GameMasterBackend for GroqBackend {
+    fn name(&self) -> &str {
+        "groq"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn endpoint(&self) -> Option<Endpoint<'_>> {
+        Some(Endpoint {
+            url: "https://api.groq.com/openai/v1/chat/completions",
+            api_key: Some(&self.api_key),
+        })
+    }
+}
+
+/// Any hosted endpoint that implements the OpenAI chat completions API.
+pub struct OpenAiBackend {
+    url: String,
+    api_key: Option<String>,
+    model: String,
+}
+
+impl OpenAiBackend {
+    pub fn from_env() -> Self {
+        let base_url = env::var("GM_BASE_URL").unwrap_or_else(|_| "https://api.openai.com/v1".to_string());
+        Self {
+            url: completions_url(&base_url),
+            api_key: env::var("OPENAI_API_KEY").ok(),
+            model: env::var("GM_MODEL").unwrap_or_else(|_| "gpt-4o".to_string()),
+        }
+    }
+}
+
+impl GameMasterBackend for OpenAiBackend {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn endpoint(&self) -> Option<Endpoint<'_>> {
+        Some(Endpoint {
+            url: &self.url,
+            api_key: self.api_key.as_deref(),
+        })
+    }
+}
+
+/// A model served on this machine, e.g. Ollama or the llama.cpp server.
+/// Both expose an OpenAI compatible endpoint and need no key. Many local models
+/// ignore or mangle tool definitions, so tools are only offered with `GM_LOCAL_TOOLS`.
+pub struct LocalBackend {
+    url: String,
+    model: String,
+    tools: bool,
+}
+
+impl LocalBackend {
+    pub fn from_env() -> Self {
+        // Ollama's default port; llama.cpp users point GM_BASE_URL at http://localhost:8080/v1
+        let base_url = env::var("GM_BASE_URL").unwrap_or_else(|_| "http://localhost:11434/v1".to_string());
+        Self {
+            url: completions_url(&base_url),
+            model: env::var("GM_MODEL").unwrap_or_else(|_| "llama3".to_string()),
+            tools: env_flag("GM_LOCAL_TOOLS"),
+        }
+    }
+}
+
+impl GameMasterBackend for LocalBackend {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn endpoint(&self) -> Option<Endpoint<'_>> {
+        Some(Endpoint {
+            url: &self.url,
+            api_key: None,
+        })
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.tools
+    }
+}
+
+/// Plays back canned responses instead of calling a model, for tests and offline demos.
+///
+/// The script is either a recorded session (the JSON written by `GM_RECORD`), whose
+/// assistant turns are replayed in order, or a file with one `Action` JSON per line.
+pub struct ScriptedBackend {
+    responses: VecDeque<String>,
+}
+
+impl ScriptedBackend {
+    pub fn from_env() -> Self {
+        let path = env::var("GM_SCRIPT").expect("GM_SCRIPT must be set for the mock backend");
+        let script = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+        Self::from_script(&script)
+    }
+
+    pub fn from_script(script: &str) -> Self {
+        let responses = match serde_json::from_str::<AllMessages>(script) {
+            Ok(session) => session.messages.into_iter()
+                .filter(|message| message.role == "assistant")
+                .map(|message| message.content)
+                .collect(),
+            Err(_) => script.lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect(),
+        };
+        Self { responses }
+    }
+}
+
+impl GameMasterBackend for ScriptedBackend {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn model(&self) -> &str {
+        "script"
+    }
+
+    fn endpoint(&self) -> Option<Endpoint<'_>> {
+        None
+    }
+
+    fn supports_tools(&self) -> bool {
+        false
+    }
+
+    fn request(&mut self, _body: &ChatCompletionRequest) -> BackendReply {
+        let content = self.responses.pop_front().unwrap_or_else(|| {
+            r#"{"action":"Sorry","value":{"error":"The script has no more moves."}}"#.to_string()
+        });
+        BackendReply::Ready(ChatCompletionResponse::from_content(self.model(), content))
+    }
+}
+
+fn completions_url(base_url: &str) -> String {
+    format!("{}/chat/completions", base_url.trim_end_matches('/'))
+}
+
+/// The backend every game master request goes through, chosen once at startup.
+#[derive(Resource)]
+pub struct GameMaster(pub Box<dyn GameMasterBackend>);
+
+impl GameMaster {
+    /// Picks a backend from `GM_BACKEND` (`groq`, `openai`, `local` or `mock`), defaulting to Groq.
+    pub fn from_env() -> Self {
+        let backend: Box<dyn GameMasterBackend> = match env::var("GM_BACKEND").as_deref() {
+            Ok("openai") => Box::new(OpenAiBackend::from_env()),
+            Ok("local") | Ok("ollama") | Ok("llamacpp") => Box::new(LocalBackend::from_env()),
+            Ok("mock") => Box::new(ScriptedBackend::from_env()),
+            Ok("groq") | Err(_) => Box::new(GroqBackend::from_env()),
+            Ok(other) => panic!("Unknown GM_BACKEND: {}", other),
+        };
+        println!("Game master backend: {} ({})", backend.name(), backend.model());
+        Self(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_content(backend: &mut ScriptedBackend) -> String {
+        let body = ChatCompletionRequest::new("script", vec![]);
+        match backend.request(&body) {
+            BackendReply::Ready(response) => response.content().to_string(),
+            BackendReply::Pending(_) => panic!("scripted backend went to the network"),
+        }
+    }
+
+    #[test]
+    fn test_scripted_backend_replays_lines_then_apologizes() {
+        let mut backend = ScriptedBackend::from_script("first\n\n  second  \n");
+        assert_eq!(next_content(&mut backend), "first");
+        assert_eq!(next_content(&mut backend), "second");
+        assert!(next_content(&mut backend).contains("Sorry"));
+    }
+
+    #[test]
+    fn test_scripted_backend_replays_recorded_session() {
+        let session = r#"{"messages":[
+            {"role":"system","content":"rules"},
+            {"role":"user","content":"Let's play a game!"},
+            {"role":"assistant","content":"board"}
+        ]}"#;
+        let mut backend = ScriptedBackend::from_script(session);
+        assert_eq!(next_content(&mut backend), "board");
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use bevy::prelude::*;
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use serde_json::{json, Value};
+use crate::{Point, HEIGHT, WIDTH};
+
+/// Largest board the game master can ask for, in either direction
+pub const MAX_GRID_SIZE: u8 = 64;
+
+/// The board as the game sees it. `update_map` keeps it in sync with what is drawn,
+/// and a compact copy goes to the game master with every turn.
+#[derive(Resource, Clone)]
+pub struct GridState {
+    pub width: u8,
+    pub height: u8,
+    cells: Vec<Color>,
+    // Cells to redraw, so the renderer only touches what changed
+    changed: Vec<usize>,
+    redraw_all: bool,
+}
+
+/// The nearest board size `GridState` supports
+pub fn supported_size(width: u8, height: u8) -> (u8, u8) {
+    (width.clamp(1, MAX_GRID_SIZE), height.clamp(1, MAX_GRID_SIZE))
+}
+
+impl GridState {
+    /// Sizes are clamped to `1..=MAX_GRID_SIZE`
+    pub fn new(width: u8, height: u8) -> Self {
+        let (width, height) = supported_size(width, height);
+        Self {
+            width,
+            height,
+            cells: vec![Color::WHITE; width as usize * height as usize],
+            changed: vec![],
+            redraw_all: true,
+        }
+    }
+
+    fn index(&self, point: &Point) -> Option<usize> {
+        (point.x < self.width && point.y < self.height)
+            .then(|| point.y as usize * self.width as usize + point.x as usize)
+    }
+
+    pub fn get(&self, point: &Point) -> Option<Color> {
+        self.index(point).map(|index| self.cells[index])
+    }
+
+    /// Returns false if the point is off the board
+    pub fn set(&mut self, point: &Point, color: Color) -> bool {
+        match self.index(point) {
+            Some(index) => {
+                self.cells[index] = color;
+                if !self.redraw_all {
+                    self.changed.push(index);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.fill(Color::WHITE);
+        self.changed.clear();
+        self.redraw_all = true;
+    }
+
+    /// Every cell that isn't white
+    pub fn painted(&self) -> impl Iterator<Item = (Point, Color)> + '_ {
+        self.cells.iter().enumerate()
+            .filter(|(_, color)| **color != Color::WHITE)
+            .map(|(index, color)| {
+                let x = (index % self.width as usize) as u8;
+                let y = (index / self.width as usize) as u8;
+                (Point { x, y }, *color)
+            })
+    }
+
+    /// The board size and every non-white cell grouped by color, e.g.
+    /// `{"width":20,"height":20,"cells":{"#ff0000":[[1,2],[3,2]]}}`
+    pub fn compact(&self) -> String {
+        self.to_json().to_string()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut cells: BTreeMap<String, Vec<[u8; 2]>> = BTreeMap::new();
+        for (point, color) in self.painted() {
+            cells.entry(to_hex(color)).or_default().push([point.x, point.y]);
+        }
+
+        json!({ "width": self.width, "height": self.height, "cells": cells })
+    }
+}
+
+/// Texture pixels per cell, including the one pixel gap on its right and top edges
+const CELL_PIXELS: u32 = 8;
+const GAP: [u8; 4] = [0, 0, 0, 255];
+
+/// The sprite the whole board is drawn on, one texture for every cell
+#[derive(Component)]
+pub struct Board;
+
+pub fn spawn_board(mut commands: Commands, mut images: ResMut<Assets<Image>>, grid: Res<GridState>) {
+    commands.spawn((
+        SpriteBundle {
+            texture: images.add(board_image(&grid)),
+            ..default()
+        },
+        Board,
+    ));
+}
+
+fn board_image(grid: &GridState) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width: grid.width as u32 * CELL_PIXELS,
+            height: grid.height as u32 * CELL_PIXELS,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &GAP,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::nearest();
+    image
+}
+
+/// Copies changed cells into the board texture and keeps the sprite fitted to the window
+pub fn draw_grid(
+    mut grid: ResMut<GridState>,
+    mut images: ResMut<Assets<Image>>,
+    mut boards: Query<(&mut Sprite, &Handle<Image>), With<Board>>,
+    windows: Query<&Window>,
+) {
+    let Ok((mut sprite, texture)) = boards.get_single_mut() else {
+        return;
+    };
+
+    let size = board_size(&grid, windows.get_single().ok());
+    if sprite.custom_size != Some(size) {
+        sprite.custom_size = Some(size);
+    }
+
+    // Draining the change list isn't a change to the board itself
+    let grid = grid.bypass_change_detection();
+    if !grid.redraw_all && grid.changed.is_empty() {
+        return;
+    }
+    let Some(image) = images.get_mut(texture) else {
+        return;
+    };
+
+    let texture_width = grid.width as u32 * CELL_PIXELS;
+    if image.width() != texture_width || image.height() != grid.height as u32 * CELL_PIXELS {
+        *image = board_image(grid);
+        grid.redraw_all = true;
+    }
+
+    if grid.redraw_all {
+        for index in 0..grid.cells.len() {
+            paint_cell(image, grid, index);
+        }
+    } else {
+        for &index in &grid.changed {
+            paint_cell(image, grid, index);
+        }
+    }
+    grid.changed.clear();
+    grid.redraw_all = false;
+}
+
+fn paint_cell(image: &mut Image, grid: &GridState, index: usize) {
+    let texture_width = grid.width as usize * CELL_PIXELS as usize;
+    let x = index % grid.width as usize;
+    // Row 0 of the texture is the top of the board, but y counts up from the bottom
+    let y = grid.height as usize - 1 - index / grid.width as usize;
+    let color = grid.cells[index].as_rgba_u8();
+
+    for row in 1..CELL_PIXELS as usize {
+        let start = ((y * CELL_PIXELS as usize + row) * texture_width + x * CELL_PIXELS as usize) * 4;
+        for pixel in image.data[start..start + (CELL_PIXELS as usize - 1) * 4].chunks_exact_mut(4) {
+            pixel.copy_from_slice(&color);
+        }
+    }
+}
+
+/// How big the board is drawn: a bit over half the window, keeping cells square
+pub fn board_size(grid: &GridState, window: Option<&Window>) -> Vec2 {
+    let (window_width, window_height) = window
+        .map(|window| (window.width(), window.height()))
+        .unwrap_or((WIDTH, HEIGHT));
+    let step = (window_width / grid.width as f32).min(window_height / grid.height as f32) * 0.6;
+    Vec2::new(grid.width as f32 * step, grid.height as f32 * step)
+}
+
+/// The cell under a world position, for a board drawn `size` big around `center`
+pub fn cell_at(grid: &GridState, center: Vec2, size: Vec2, position: Vec2) -> Option<Point> {
+    // From the bottom left corner, where (0, 0) is
+    let offset = (position - center + size / 2.0) / size;
+    if !(0.0..1.0).contains(&offset.x) || !(0.0..1.0).contains(&offset.y) {
+        return None;
+    }
+    Some(Point {
+        x: (offset.x * grid.width as f32) as u8,
+        y: (offset.y * grid.height as f32) as u8,
+    })
+}
+
+pub fn to_hex(color: Color) -> String {
+    let [r, g, b, _] = color.as_rgba_u8();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_lists_non_white_cells_by_color() {
+        let mut grid = GridState::new(4, 3);
+        grid.set(&Point { x: 3, y: 2 }, Color::rgb_u8(255, 0, 0));
+        grid.set(&Point { x: 1, y: 0 }, Color::rgb_u8(255, 0, 0));
+        grid.set(&Point { x: 0, y: 1 }, Color::rgb_u8(0, 0, 255));
+        assert!(!grid.set(&Point { x: 4, y: 0 }, Color::BLACK));
+
+        assert_eq!(
+            grid.compact(),
+            r##"{"cells":{"#0000ff":[[0,1]],"#ff0000":[[1,0],[3,2]]},"height":3,"width":4}"##
+        );
+    }
+
+    #[test]
+    fn test_cell_at_counts_from_bottom_left() {
+        let grid = GridState::new(4, 2);
+        let size = Vec2::new(400.0, 200.0);
+        assert!(cell_at(&grid, Vec2::ZERO, size, Vec2::new(-199.0, -99.0)) == Some(Point { x: 0, y: 0 }));
+        assert!(cell_at(&grid, Vec2::ZERO, size, Vec2::new(120.0, 10.0)) == Some(Point { x: 3, y: 1 }));
+        assert!(cell_at(&grid, Vec2::new(100.0, 0.0), size, Vec2::new(-50.0, 0.0)) == Some(Point { x: 0, y: 1 }));
+        assert!(cell_at(&grid, Vec2::ZERO, size, Vec2::new(201.0, 0.0)).is_none());
+    }
+}
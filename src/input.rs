@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::{env, fs};
+use bevy::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use crate::actions::{CustomAction, SceneUpdate};
+use crate::grid::{board_size, cell_at, Board, GridState};
+use crate::{EguiWantsFocus, MovementEvent, Point};
+
+/// Turns keyboard and gamepad input into `MovementEvent`s using the configured bindings
+pub struct InputPlugin;
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputBindings::from_env())
+            .init_resource::<CustomActions>()
+            .add_event::<MovementEvent>()
+            .add_event::<CellClickEvent>()
+            .add_systems(Update, declare_actions)
+            // Don't move while the player is typing in the chat box or clicking on it
+            .add_systems(Update, keyboard_input.run_if(resource_equals(EguiWantsFocus(false))))
+            .add_systems(Update, pointer_input.run_if(resource_equals(EguiWantsFocus(false))))
+            .add_systems(Update, gamepad_input);
+    }
+}
+
+/// The player clicked or tapped a cell on the board
+#[derive(Event, Clone)]
+pub struct CellClickEvent {
+    pub point: Point,
+}
+
+/// Which keys and gamepad buttons trigger which moves. Loaded from the JSON file at `GM_BINDINGS`,
+/// e.g. `{"keys":{"KeyA":"left","Space":"action"},"buttons":{"South":"action"},"repeat_delay":0.3}`.
+/// Fields left out of the file keep their defaults.
+#[derive(Resource, Deserialize)]
+#[serde(default)]
+pub struct InputBindings {
+    pub keys: HashMap<KeyCode, MovementEvent>,
+    pub buttons: HashMap<GamepadButtonType, MovementEvent>,
+    /// Held to talk to the game master, when voice input is set up
+    pub push_to_talk: KeyCode,
+    /// Stops the narration line that is playing
+    pub skip_narration: KeyCode,
+    /// How far the left stick has to be pushed before it counts, from 0 to 1
+    pub stick_deadzone: f32,
+    /// Seconds a direction is held before it starts repeating
+    pub repeat_delay: f32,
+    /// Seconds between repeats after that
+    pub repeat_interval: f32,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        Self {
+            keys: HashMap::from([
+                (KeyCode::ArrowLeft, MovementEvent::Left),
+                (KeyCode::ArrowRight, MovementEvent::Right),
+                (KeyCode::ArrowUp, MovementEvent::Up),
+                (KeyCode::ArrowDown, MovementEvent::Down),
+                (KeyCode::Space, MovementEvent::Action),
+                (KeyCode::Enter, MovementEvent::Confirm),
+                (KeyCode::Escape, MovementEvent::Cancel),
+            ]),
+            buttons: HashMap::from([
+                (GamepadButtonType::DPadLeft, MovementEvent::Left),
+                (GamepadButtonType::DPadRight, MovementEvent::Right),
+                (GamepadButtonType::DPadUp, MovementEvent::Up),
+                (GamepadButtonType::DPadDown, MovementEvent::Down),
+                (GamepadButtonType::South, MovementEvent::Action),
+                (GamepadButtonType::Start, MovementEvent::Confirm),
+                (GamepadButtonType::East, MovementEvent::Cancel),
+            ]),
+            push_to_talk: KeyCode::KeyV,
+            skip_narration: KeyCode::Tab,
+            stick_deadzone: 0.5,
+            repeat_delay: 0.4,
+            repeat_interval: 0.15,
+        }
+    }
+}
+
+impl InputBindings {
+    pub fn from_env() -> Self {
+        let Ok(path) = env::var("GM_BINDINGS") else {
+            return Self::default();
+        };
+        let bindings = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e));
+        serde_json::from_str(&bindings).unwrap_or_else(|e| panic!("Failed to parse {}: {}", path, e))
+    }
+}
+
+/// Controls the game master declared for the current game, listed in the UI
+#[derive(Resource, Default)]
+pub struct CustomActions {
+    pub actions: Vec<CustomAction>,
+    // Suggested keys that exist, these win over the configured bindings
+    keys: HashMap<KeyCode, String>,
+}
+
+impl CustomActions {
+    fn new(actions: Vec<CustomAction>) -> Self {
+        let keys = actions.iter().filter_map(|action| {
+            let key = action.key.as_ref()?;
+            match serde_json::from_value::<KeyCode>(json!(key)) {
+                Ok(code) => Some((code, action.name.clone())),
+                Err(_) => {
+                    println!("Unknown key {} for action {}", key, action.name);
+                    None
+                }
+            }
+        }).collect();
+        Self { actions, keys }
+    }
+}
+
+fn declare_actions(mut scene_updates: EventReader<SceneUpdate>, mut custom_actions: ResMut<CustomActions>) {
+    for event in scene_updates.read() {
+        if let SceneUpdate::DeclareActions { actions } = event {
+            println!("Actions: {}", actions.iter().map(|action| action.name.as_str()).collect::<Vec<_>>().join(", "));
+            *custom_actions = CustomActions::new(actions.clone());
+        }
+    }
+}
+
+fn keyboard_input(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    custom_actions: Res<CustomActions>,
+    mut action_writer: EventWriter<MovementEvent>,
+) {
+    for key in keyboard.get_just_pressed() {
+        if let Some(name) = custom_actions.keys.get(key) {
+            action_writer.send(MovementEvent::Custom(name.clone()));
+        } else if let Some(movement) = bindings.keys.get(key) {
+            action_writer.send(movement.clone());
+        }
+    }
+}
+
+fn gamepad_input(
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    buttons: Res<ButtonInput<GamepadButton>>,
+    bindings: Res<InputBindings>,
+    time: Res<Time>,
+    mut stick: Local<StickRepeat>,
+    mut action_writer: EventWriter<MovementEvent>,
+) {
+    let Some(gamepad) = gamepads.iter().next() else {
+        return;
+    };
+
+    for button in buttons.get_just_pressed().filter(|button| button.gamepad == gamepad) {
+        if let Some(movement) = bindings.buttons.get(&button.button_type) {
+            action_writer.send(movement.clone());
+        }
+    }
+
+    let x = axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickX)).unwrap_or(0.0);
+    let y = axes.get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickY)).unwrap_or(0.0);
+    if let Some(movement) = stick.update(stick_direction(x, y, bindings.stick_deadzone), time.delta_seconds(), &bindings) {
+        action_writer.send(movement);
+    }
+}
+
+fn pointer_input(
+    mouse: Res<ButtonInput<MouseButton>>,
+    touches: Res<Touches>,
+    windows: Query<&Window>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    boards: Query<&GlobalTransform, With<Board>>,
+    grid: Res<GridState>,
+    mut click_writer: EventWriter<CellClickEvent>,
+) {
+    let (Ok(window), Ok((camera, camera_transform)), Ok(board)) = (windows.get_single(), cameras.get_single(), boards.get_single()) else {
+        return;
+    };
+
+    let clicks = mouse.just_pressed(MouseButton::Left)
+        .then(|| window.cursor_position())
+        .flatten()
+        .into_iter()
+        .chain(touches.iter_just_pressed().map(|touch| touch.position()));
+    let size = board_size(&grid, Some(window));
+    for click in clicks {
+        let Some(position) = camera.viewport_to_world_2d(camera_transform, click) else {
+            continue;
+        };
+        if let Some(point) = cell_at(&grid, board.translation().truncate(), size, position) {
+            click_writer.send(CellClickEvent { point });
+        }
+    }
+}
+
+/// The direction the stick points in most, if it is outside the deadzone
+fn stick_direction(x: f32, y: f32, deadzone: f32) -> Option<MovementEvent> {
+    if x.abs().max(y.abs()) < deadzone {
+        None
+    } else if x.abs() > y.abs() {
+        Some(if x < 0.0 { MovementEvent::Left } else { MovementEvent::Right })
+    } else {
+        Some(if y > 0.0 { MovementEvent::Up } else { MovementEvent::Down })
+    }
+}
+
+/// Fires once when the stick is pushed, then repeats after a delay while it stays there
+#[derive(Default)]
+struct StickRepeat {
+    held: Option<MovementEvent>,
+    until_repeat: f32,
+}
+
+impl StickRepeat {
+    fn update(&mut self, direction: Option<MovementEvent>, delta: f32, bindings: &InputBindings) -> Option<MovementEvent> {
+        if direction != self.held {
+            self.held = direction.clone();
+            self.until_repeat = bindings.repeat_delay;
+            return direction;
+        }
+
+        let held = self.held.clone()?;
+        self.until_repeat -= delta;
+        if self.until_repeat > 0.0 {
+            return None;
+        }
+        self.until_repeat += bindings.repeat_interval;
+        Some(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stick_fires_on_push_then_repeats_after_delay() {
+        let bindings = InputBindings { repeat_delay: 0.4, repeat_interval: 0.1, ..default() };
+        let mut stick = StickRepeat::default();
+        let right = stick_direction(0.9, 0.2, bindings.stick_deadzone);
+
+        assert_eq!(stick.update(stick_direction(0.3, 0.1, bindings.stick_deadzone), 0.1, &bindings), None);
+        assert_eq!(stick.update(right.clone(), 0.1, &bindings), Some(MovementEvent::Right));
+        assert_eq!(stick.update(right.clone(), 0.3, &bindings), None);
+        assert_eq!(stick.update(right.clone(), 0.15, &bindings), Some(MovementEvent::Right));
+        assert_eq!(stick.update(right.clone(), 0.03, &bindings), None);
+        assert_eq!(stick.update(right, 0.05, &bindings), Some(MovementEvent::Right));
+        assert_eq!(stick.update(None, 0.1, &bindings), None);
+    }
+
+    #[test]
+    fn test_bindings_file_overrides_defaults() {
+        let bindings: InputBindings = serde_json::from_str(r#"{"keys":{"KeyA":"left","Space":"confirm"},"repeat_delay":0.2}"#).unwrap();
+        assert_eq!(bindings.keys.get(&KeyCode::KeyA), Some(&MovementEvent::Left));
+        assert_eq!(bindings.keys.get(&KeyCode::Space), Some(&MovementEvent::Confirm));
+        assert_eq!(bindings.buttons.get(&GamepadButtonType::South), Some(&MovementEvent::Action));
+        assert_eq!(bindings.repeat_delay, 0.2);
+    }
+
+    #[test]
+    fn test_custom_actions_bind_known_keys() {
+        let actions: Vec<CustomAction> = serde_json::from_str(
+            r#"[{"name":"fire","key":"KeyF"},{"name":"jump","key":"the big one"},{"name":"pause"}]"#
+        ).unwrap();
+        let custom_actions = CustomActions::new(actions);
+        assert_eq!(custom_actions.actions.len(), 3);
+        assert_eq!(custom_actions.keys.len(), 1);
+        assert_eq!(custom_actions.keys.get(&KeyCode::KeyF).map(String::as_str), Some("fire"));
+    }
+}
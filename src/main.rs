@@ -1,19 +1,27 @@
+use std::env;
+use std::time::Duration;
 use bevy::{
+    app::ScheduleRunnerPlugin,
     prelude::*
     ,
     window::{WindowResolution},
 };
 use bevy::render::render_resource::{AsBindGroup, ShaderType};
-use bevy::sprite::{ MaterialMesh2dBundle, Mesh2dHandle};
 use bevy_egui::EguiPlugin;
 use dotenv::dotenv;
 use rand::Rng;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use crate::actions::SceneUpdate;
-use crate::audio_plugin::{request_audio_system, RequestAudioEvent};
-use crate::network::{chat_writer, GroqPlugin, Prompt};
+use crate::audio_plugin::{NarrationPlugin, NarrationPriority, RequestAudioEvent, LOSE_LINE, WIN_LINE};
+use crate::grid::{draw_grid, spawn_board, GridState};
+use crate::input::InputPlugin;
+use crate::network::{chat_writer, click_writer, env_flag, GameMasterPlugin, Prompt, ResponseSystems};
+use crate::rules::{RulesPlugin, RulesSystems};
 use crate::server::ServerPlugin;
+use crate::sound::SoundPlugin;
 use crate::ui::UIPlugin;
+use crate::voice::VoicePlugin;
 
 mod screen_space_quad;
 mod custom_material;
@@ -23,6 +31,16 @@ mod server;
 mod actions;
 mod base_screen_space_material;
 mod audio_plugin;
+mod backend;
+mod grid;
+mod input;
+mod rules;
+mod sound;
+mod speech_cache;
+mod streaming;
+mod tts;
+mod validation;
+mod voice;
 
 pub const WIDTH: f32 = 720.0;
 pub const HEIGHT: f32 = 720.0;
@@ -31,52 +49,101 @@ pub const HEIGHT: f32 = 720.0;
 #[derive(Debug, Clone, Copy, SystemSet, PartialEq, Eq, Hash)]
 pub struct CamSystemSet;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Event)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Event, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum MovementEvent {
     Left,
     Right,
     Up,
     Down,
+    /// The main button, e.g. jump or fire
+    Action,
+    Confirm,
+    Cancel,
+    /// One of the actions the game master declared for the current game
+    Custom(String),
+}
+
+impl MovementEvent {
+    /// How the move is spelled for the game master and for rules scripts
+    fn name(&self) -> &str {
+        match self {
+            MovementEvent::Left => "left",
+            MovementEvent::Right => "right",
+            MovementEvent::Up => "up",
+            MovementEvent::Down => "down",
+            MovementEvent::Action => "action",
+            MovementEvent::Confirm => "confirm",
+            MovementEvent::Cancel => "cancel",
+            MovementEvent::Custom(name) => name,
+        }
+    }
+
+    /// The opposite of `name`. Anything that isn't a built in move is taken to be a custom action.
+    fn from_name(name: &str) -> Self {
+        match name {
+            "left" => MovementEvent::Left,
+            "right" => MovementEvent::Right,
+            "up" => MovementEvent::Up,
+            "down" => MovementEvent::Down,
+            "action" => MovementEvent::Action,
+            "confirm" => MovementEvent::Confirm,
+            "cancel" => MovementEvent::Cancel,
+            name => MovementEvent::Custom(name.to_string()),
+        }
+    }
 }
 
 fn main() {
     dotenv().ok();
     let mut app = App::new();
 
-    app.insert_resource(ClearColor(Color::rgb(0.0, 0.0, 0.0)))
-        .insert_resource(Msaa::Sample4)
-        .add_plugins(
-            DefaultPlugins
-                .set(WindowPlugin {
-                    primary_window: Some(Window {
-                        resolution: WindowResolution::new(WIDTH, HEIGHT),
-                        title: "Sim".to_string(),
-                        resizable: true,
+    if headless() {
+        // No window, rendering or audio. The board only lives in `GridState` and is played over the HTTP API.
+        app.add_plugins(MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0))))
+            .add_plugins(bevy::input::InputPlugin)
+            .add_event::<RequestAudioEvent>();
+    } else {
+        app.insert_resource(ClearColor(Color::rgb(0.0, 0.0, 0.0)))
+            .insert_resource(Msaa::Sample4)
+            .add_plugins(
+                DefaultPlugins
+                    .set(WindowPlugin {
+                        primary_window: Some(Window {
+                            resolution: WindowResolution::new(WIDTH, HEIGHT),
+                            title: "Sim".to_string(),
+                            resizable: true,
+                            ..default()
+                        }),
+                        ..default()
+                    })
+                    .set(AssetPlugin {
                         ..default()
-                    }),
-                    ..default()
-                })
-                .set(AssetPlugin {
-                    ..default()
-                })
-        )
-        .add_plugins(ServerPlugin)
-        .add_plugins(GroqPlugin)
-        // .add_plugins(Material2dPlugin::<CustomMaterial>::default())
-        // .add_plugins(MaterialPlugin::<ScreenSpaceMaterial>::default())
-        .add_plugins((EguiPlugin, UIPlugin))
+                    })
+            )
+            // .add_plugins(Material2dPlugin::<CustomMaterial>::default())
+            // .add_plugins(MaterialPlugin::<ScreenSpaceMaterial>::default())
+            .add_plugins((EguiPlugin, UIPlugin))
+            .add_plugins(VoicePlugin)
+            .add_plugins(NarrationPlugin)
+            .add_plugins(SoundPlugin)
+            .add_systems(Startup, (setup, spawn_board))
+            .add_systems(Update, draw_grid.after(update_map))
+            .add_systems(PostUpdate, check_egui_wants_focus);
+    }
+
+    app.add_plugins(ServerPlugin)
+        .add_plugins(GameMasterPlugin)
+        .add_plugins(RulesPlugin)
+        .add_plugins(InputPlugin)
         //Create the aspect ratio as a resource. Only one instance of this data is needed so a global resource was chosen
         .init_resource::<Prompt>()
-        .add_event::<MovementEvent>()
-        .add_event::<RequestAudioEvent>()
-        .add_systems(Startup, setup)
-        // .add_systems(Update, request_audio_system)
-        .add_systems(Update, keyboard_input)
-        .add_systems(Update, update_map)
-        .add_systems(Update, chat_writer);
+        .insert_resource(GridState::new(20, 20))
+        .init_resource::<GameStatus>()
+        .add_systems(Update, update_map.after(ResponseSystems))
+        .add_systems(Update, (chat_writer, click_writer).before(RulesSystems));
 
     app.init_resource::<EguiWantsFocus>()
-        .add_systems(PostUpdate, check_egui_wants_focus)
         .configure_sets(
             Update,
             CamSystemSet.run_if(resource_equals(EguiWantsFocus(false))),
@@ -85,6 +152,11 @@ fn main() {
     app.run();
 }
 
+/// `--headless` or `GM_HEADLESS=1` runs without a window, e.g. on a server or in CI
+fn headless() -> bool {
+    env::args().any(|arg| arg == "--headless") || env_flag("GM_HEADLESS")
+}
+
 #[derive(Resource, Deref, DerefMut, PartialEq, Eq, Default)]
 struct EguiWantsFocus(bool);
 
@@ -103,50 +175,29 @@ fn check_egui_wants_focus(
     wants_focus.set_if_neq(EguiWantsFocus(new_wants_focus));
 }
 
-#[derive(Component, Deserialize, Serialize, Clone, PartialEq)]
+// The schema's upper bounds are filled in from the current grid size, see `actions::action_schema`
+#[derive(Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 struct Point { x: u8, y: u8 }
 
-fn setup(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-) {
+fn setup(mut commands: Commands) {
     // Camera
     commands.spawn(Camera2dBundle::default());
+}
 
-    let padding = 2;
-    let count: usize = 20;
-    let size: usize = 20;
-    let width = WIDTH + (padding * (count - 1)) as f32;
-    let height = HEIGHT + (padding * (count - 1)) as f32;
-
-
-    for i in 0..count {
-        for j in 0..count {
-            commands.spawn((
-                MaterialMesh2dBundle {
-                    mesh: Mesh2dHandle(meshes.add(Rectangle::new(
-                        (size) as f32, (size) as f32
-                    ))),
-                    material: materials.add(Color::rgb(1.0, 1.0, 1.0)),
-                    transform: Transform::from_xyz(
-                        -width * 0.25 + (i * (size + padding)) as f32 - padding as f32,
-                        -height * 0.25 + (j * (size + padding)) as f32 - padding as f32,
-                        1.0
-                    ),
-                    ..default()
-                },
-                Point { x: i as u8, y: j as u8 },
-            ));
-        }
-    }
+/// Everything about the game besides the board, as of the last update
+#[derive(Resource, Default)]
+pub struct GameStatus {
+    pub message: Option<String>,
+    pub score: Option<i64>,
+    /// `Some(true)` once the player has won, `Some(false)` once they've lost
+    pub game_end: Option<bool>,
 }
 
 fn update_map(
     mut event_reader: EventReader<SceneUpdate>,
     mut event_writer: EventWriter<RequestAudioEvent>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    mut query: Query<(&Point, &mut Handle<ColorMaterial>)>,
+    mut grid: ResMut<GridState>,
+    mut status: ResMut<GameStatus>,
 ) {
     for event in event_reader.read() {
         match event {
@@ -155,103 +206,64 @@ fn update_map(
                 update_points,
                 game_end,
                 message,
+                score,
             } => {
+                if score.is_some() {
+                    status.score = *score;
+                }
+
                 if let Some(clear_grid) = clear_grid {
                     if *clear_grid {
-                        // clear grid
-                        for (_, col) in materials.iter_mut() {
-                            col.color = Color::rgb(1.0, 1.0, 1.0);
-                        }
+                        grid.clear();
+                        // A cleared board is a game that hasn't ended yet
+                        status.game_end = None;
                     }
                 }
+                if game_end.is_some() {
+                    status.game_end = *game_end;
+                }
 
                 for point_color in update_points {
-                    let current_point = point_color.point.clone();
-                    for (point, material) in query.iter() {
-                        if *point == current_point {
-                            materials.get_mut(material).unwrap().color = point_color.color;
-                        }
-                    }
+                    grid.set(&point_color.point, point_color.color);
                 }
 
                 if let Some(game_end) = game_end {
                     println!("Win?: {}", game_end);
                     let text = if *game_end {
-                        "Everyone wins sometimes."
+                        WIN_LINE
                     } else {
-                        "Game over man, game over."
+                        LOSE_LINE
                     };
                     event_writer.send(RequestAudioEvent {
                         text: text.to_string(),
+                        priority: NarrationPriority::High,
                     });
                 }
 
                 if let Some(message) = message {
                     println!("Message: {}", message);
+                    status.message = Some(message.clone());
                     event_writer.send(RequestAudioEvent {
                         text: message.to_string(),
+                        priority: NarrationPriority::Normal,
                     });
                 }
             }
+            SceneUpdate::SetGridSize { width, height } => {
+                println!("Grid size: {}x{}", width, height);
+                *grid = GridState::new(*width, *height);
+                // A new board is a new game
+                *status = GameStatus::default();
+            }
+            // Picked up by the rules, input and sound plugins
+            SceneUpdate::SetRules { .. } | SceneUpdate::DeclareActions { .. } | SceneUpdate::PlaySound(_) => {}
             SceneUpdate::Sorry { error } => {
                 println!("Error Message: {}", error);
                 event_writer.send(RequestAudioEvent {
                     text: error.to_string(),
+                    priority: NarrationPriority::Low,
                 });
             }
         }
     }
 }
-
-fn gamepad_input(
-    gamepads: Res<Gamepads>,
-    axes: Res<Axis<GamepadAxis>>,
-    mut action_writer: EventWriter<MovementEvent>,
-) {
-    let gamepad = gamepads.iter().next();
-
-    if let Some(gamepad) = gamepad {
-        let left_stick_x = axes
-            .get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickX))
-            .unwrap_or(0.0);
-
-        let left_stick_y = axes
-            .get(GamepadAxis::new(gamepad, GamepadAxisType::LeftStickY))
-            .unwrap_or(0.0);
-
-        if left_stick_x < -0.5 {
-            action_writer.send(MovementEvent::Left);
-        } else if left_stick_x > 0.5 {
-            action_writer.send(MovementEvent::Right);
-        } else if left_stick_y < -0.5 {
-            action_writer.send(MovementEvent::Up);
-        } else if left_stick_y > 0.5 {
-            action_writer.send(MovementEvent::Down);
-        }
-    }
-}
-
-fn keyboard_input(
-    keyboard: Res<ButtonInput<KeyCode>>,
-    mut action_writer: EventWriter<MovementEvent>,
-) {
-    // Check for left movement keys
-    if keyboard.just_pressed(KeyCode::ArrowLeft) {
-        action_writer.send(MovementEvent::Left);
-    }
-
-    // Check for right movement keys
-    if keyboard.just_pressed(KeyCode::ArrowRight) {
-        action_writer.send(MovementEvent::Right);
-    }
-
-    // Check for left movement keys
-    if keyboard.just_pressed(KeyCode::ArrowUp) {
-        action_writer.send(MovementEvent::Up);
-    }
-
-    // Check for right movement keys
-    if keyboard.just_pressed(KeyCode::ArrowDown) {
-        action_writer.send(MovementEvent::Down);
-    }
-}
\ No newline at end of file
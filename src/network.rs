@@ -1,10 +1,19 @@
-use std::env;
+use std::collections::VecDeque;
+use std::{env, fs};
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use bevy_http_client::{HttpClient, HttpClientPlugin};
-use bevy_http_client::prelude::{HttpTypedRequestTrait, TypedRequest, TypedResponse};
-use crate::actions::{build_system_prompt, handle_request, SceneUpdate};
-use crate::MovementEvent;
+use bevy_http_client::HttpClientPlugin;
+use bevy_http_client::prelude::{HttpTypedRequestTrait, TypedRequest, TypedResponse, TypedResponseError};
+use crate::actions::{action_tools, build_system_prompt, handle_request, parse_tool_call, Applied, PointColor, SceneUpdate};
+use crate::audio_plugin::LOST_TRACK_LINE;
+use crate::backend::{BackendReply, GameMaster};
+use crate::grid::GridState;
+use crate::rules::Rules;
+use crate::streaming::{Drawn, PartialUpdateParser, StreamEvent, StreamJob, StreamingClient};
+use crate::validation::{PendingViolations, PointValidation, PointValidator};
+use crate::input::CellClickEvent;
+use crate::{MovementEvent, Point};
 
 #[derive(Resource)]
 pub struct Prompt {
@@ -22,33 +31,123 @@ impl Default for Prompt {
 
 // Define request message structure
 #[derive(Deserialize, Serialize, Debug, Clone)]
-struct ChatMessage {
-    role: String,
-    content: String,
+pub(crate) struct ChatMessage {
+    pub(crate) role: String,
+    #[serde(default)]
+    pub(crate) content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 
 impl ChatMessage {
-    pub fn system_prompt() -> Self {
+    fn new(role: &str, content: String) -> Self {
         Self {
-            role: "system".to_string(),
-            content: build_system_prompt()
+            role: role.to_string(),
+            content,
+            tool_calls: None,
+            tool_call_id: None,
         }
     }
+
+    pub fn system_prompt(tool_calling: bool, grid: &GridState) -> Self {
+        Self::new("system", build_system_prompt(tool_calling, grid))
+    }
+
+    pub fn user(content: String) -> Self {
+        Self::new("user", content)
+    }
+
+    pub fn assistant(content: String, tool_calls: Option<Vec<ToolCall>>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::new("assistant", content)
+        }
+    }
+
+    /// The outcome of a tool call, which the API expects before the next user turn
+    pub fn tool_result(tool_call_id: &str, content: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Self::new("tool", content)
+        }
+    }
+
+    /// Sends an unusable response back to the model along with what was wrong with it,
+    /// asking for a fix in whichever form the model was told to answer in
+    pub fn repair_request(error: &str, response: &str, tool_calling: bool) -> Self {
+        let instruction = if tool_calling {
+            "Call the tools again with arguments that match their parameters exactly."
+        } else {
+            "Respond again with a single valid action that follows the JSON schema exactly."
+        };
+        Self::user(format!(
+            "Your last response could not be used. {}. This is what you sent: {} {}",
+            error, response, instruction
+        ))
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct ToolCall {
+    id: String,
+    r#type: String,
+    function: FunctionCall,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct FunctionCall {
+    name: String,
+    // Stringified JSON, as the API sends it
+    arguments: String,
 }
 
 // Define request structure
 #[derive(Serialize, Debug)]
-struct ChatCompletionRequest {
+pub(crate) struct ChatCompletionRequest {
     messages: Vec<ChatMessage>,
     model: String,
     temperature: f32,
     max_tokens: u32,
     top_p: f32,
-    stream: bool,
-    response_format: ResponseFormat,
+    pub(crate) stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<String>,
     stop: Option<String>,
 }
 
+impl ChatCompletionRequest {
+    pub(crate) fn new(model: &str, messages: Vec<ChatMessage>) -> Self {
+        Self {
+            messages,
+            model: model.to_string(),
+            temperature: 1.0,
+            max_tokens: 1024,
+            top_p: 1.0,
+            stream: false,
+            response_format: Some(ResponseFormat { r#type: "json_object".to_string() }),
+            tools: None,
+            tool_choice: None,
+            stop: None,
+        }
+    }
+
+    /// Offers the game actions as tools instead of asking for JSON
+    pub(crate) fn with_tools(self, grid: &GridState) -> Self {
+        Self {
+            response_format: None,
+            tools: Some(action_tools(grid)),
+            tool_choice: Some("required".to_string()),
+            ..self
+        }
+    }
+}
+
 // Define response format type
 #[derive(Serialize, Debug)]
 struct ResponseFormat {
@@ -56,16 +155,46 @@ struct ResponseFormat {
 }
 
 // Define the main response structure
+// Only `choices` is required; the rest varies between providers
 #[derive(Deserialize, Debug)]
-struct ChatCompletionResponse {
-    id: String,
-    object: String,
-    created: u64,
-    model: String,
+pub(crate) struct ChatCompletionResponse {
+    id: Option<String>,
+    object: Option<String>,
+    created: Option<u64>,
+    model: Option<String>,
     choices: Vec<Choice>,
-    usage: Usage,
-    system_fingerprint: String,
-    x_groq: Groq,
+    usage: Option<Usage>,
+    system_fingerprint: Option<String>,
+    x_groq: Option<Groq>,
+}
+
+impl ChatCompletionResponse {
+    /// Wraps a single assistant message, for backends that answer in-process
+    pub(crate) fn from_content(model: &str, content: String) -> Self {
+        Self {
+            id: None,
+            object: Some("chat.completion".to_string()),
+            created: None,
+            model: Some(model.to_string()),
+            choices: vec![Choice {
+                index: 0,
+                message: Message { role: "assistant".to_string(), content: Some(content), tool_calls: None },
+                logprobs: None,
+                finish_reason: Some("stop".to_string()),
+            }],
+            usage: None,
+            system_fingerprint: None,
+            x_groq: None,
+        }
+    }
+
+    pub(crate) fn content(&self) -> &str {
+        self.choices.first().and_then(|choice| choice.message.content.as_deref()).unwrap_or("")
+    }
+
+    fn tool_calls(&self) -> Option<&Vec<ToolCall>> {
+        self.choices.first()?.message.tool_calls.as_ref()
+    }
 }
 
 // Nested response structures
@@ -73,29 +202,30 @@ struct ChatCompletionResponse {
 struct Choice {
     index: u32,
     message: Message,
-    logprobs: Option<String>,
-    finish_reason: String,
+    logprobs: Option<serde_json::Value>,
+    finish_reason: Option<String>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct Message {
     role: String,
-    content: String,
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCall>>,
 }
 
 #[derive(Deserialize, Debug)]
 struct Usage {
     prompt_tokens: u32,
-    prompt_time: f64,
+    prompt_time: Option<f64>,
     completion_tokens: u32,
-    completion_time: f64,
+    completion_time: Option<f64>,
     total_tokens: u32,
-    total_time: f64,
+    total_time: Option<f64>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Resource)]
-struct AllMessages {
-    messages: Vec<ChatMessage>,
+pub(crate) struct AllMessages {
+    pub(crate) messages: Vec<ChatMessage>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -103,60 +233,181 @@ struct Groq {
     id: String,
 }
 
+/// A response produced without going through the HTTP client. Only bevy_http_client can build a
+/// `TypedResponse`, so in-process backends answer with this and `handle_response` reads both alike.
 #[derive(Event)]
 struct ApiResponseEvent {
     response: ChatCompletionResponse,
+    /// What was drawn while the response was streamed in
+    drawn: Drawn,
+    /// The board before that, to go back to if the response can't be used
+    drawn_over: Option<GridState>,
 }
 
-// Plugin encapsulating the network functionality
-pub struct GroqPlugin;
+/// A completion that never arrived, e.g. because the stream broke off
+#[derive(Event)]
+struct ResponseFailed {
+    error: String,
+}
 
-impl Plugin for GroqPlugin {
+/// Asks the game master to answer the conversation so far, on behalf of `turn` if someone is waiting on it
+#[derive(Event, Clone, Copy, Default)]
+struct CompletionRequest {
+    turn: Option<TurnId>,
+}
+
+/// The game master answers one input at a time, so whatever comes back belongs to the request it's answering
+#[derive(Resource, Default)]
+struct Turns {
+    /// Inputs that arrived while the game master was busy
+    waiting: VecDeque<ChatInputRequest>,
+    /// Set from the moment a request goes out until its response has been applied or given up on
+    answering: Option<CompletionRequest>,
+}
+
+// Plugin encapsulating the network functionality.
+// A `GameMaster` inserted before the plugin is used instead of the one configured in the environment.
+pub struct GameMasterPlugin;
+
+impl Plugin for GameMasterPlugin {
     fn build(&self, app: &mut App) {
+        let game_master = app.world.remove_resource::<GameMaster>().unwrap_or_else(GameMaster::from_env);
+        let tools_requested = env_flag("GM_TOOLS");
+        let tool_calling = tools_requested && game_master.0.supports_tools();
+        if tools_requested && !tool_calling {
+            println!("{} does not support tool calling, using JSON mode", game_master.0.name());
+        }
+
         app.add_plugins(HttpClientPlugin)
             .add_event::<TypedResponse<ChatCompletionResponse>>()
             .add_event::<ChatInputRequest>()
+            .add_event::<CompletionRequest>()
+            .add_event::<ApiResponseEvent>()
+            .add_event::<ResponseFailed>()
             .add_event::<SceneUpdate>()
+            .add_event::<TurnFinished>()
+            .add_event::<TrackedMove>()
+            .insert_resource(ToolCalling(tool_calling))
+            .insert_resource(game_master)
             .insert_resource(AllMessages { messages: vec![] })
+            .init_resource::<RepairAttempts>()
+            .init_resource::<Turns>()
+            .init_resource::<PointValidation>()
+            .init_resource::<PendingViolations>()
             .add_systems(Startup, initialize)
+            .add_systems(Update, refresh_system_prompt)
             .add_systems(Update, chat_reader)
-            .add_systems(Update, handle_response)
+            .add_systems(Update, send_completion_request.after(chat_reader).after(refresh_system_prompt))
+            .add_systems(Update, (
+                read_stream.run_if(resource_exists::<StreamingClient>),
+                handle_response.after(read_stream),
+            ).in_set(ResponseSystems))
             .register_request_type::<ChatCompletionResponse>();
+
+        if env_flag("GM_STREAM") {
+            app.insert_resource(StreamingClient::spawn());
+        }
     }
 }
 
-fn initialize(mut all_messages: ResMut<AllMessages>, mut ev_request: EventWriter<TypedRequest<ChatCompletionResponse>>) {
-    let api_key = env::var("GROQ_API_KEY").expect("GROQ_API_KEY must be set");
+/// System set of the systems that turn the game master's responses into scene updates. Whatever applies
+/// those updates runs after it, so the next response is checked against the board they left behind.
+#[derive(Debug, Clone, Copy, SystemSet, PartialEq, Eq, Hash)]
+pub struct ResponseSystems;
 
-    all_messages.messages.push(ChatMessage::system_prompt());
-    all_messages.messages.push(ChatMessage { role: "user".to_string(), content: "Let's play a game!".to_string() });
+pub(crate) fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value == "1" || value == "true")
+}
 
-    let request_body = ChatCompletionRequest {
-        messages: all_messages.messages.clone(),
-        model: "llama3-70b-8192".to_string(),
-        temperature: 1.0,
-        max_tokens: 1024,
-        top_p: 1.0,
-        stream: false,
-        response_format: ResponseFormat { r#type: "json_object".to_string() },
-        stop: None,
-    };
+/// Whether game actions are offered as tools rather than requested as JSON
+#[derive(Resource)]
+struct ToolCalling(bool);
+
+fn initialize(
+    tool_calling: Res<ToolCalling>,
+    grid: Res<GridState>,
+    mut all_messages: ResMut<AllMessages>,
+    mut turns: ResMut<Turns>,
+    mut completion_requests: EventWriter<CompletionRequest>,
+) {
+    all_messages.messages.push(ChatMessage::system_prompt(tool_calling.0, &grid));
+    all_messages.messages.push(ChatMessage::user("Let's play a game!".to_string()));
 
-    ev_request.send(
-        HttpClient::new()
-            .post("https://api.groq.com/openai/v1/chat/completions")
-            .headers(&[
-                ("Authorization", &format!("Bearer {}", api_key)),
-                ("Content-Type", "application/json")
-            ])
-            .json(&request_body)
-            .with_type::<ChatCompletionResponse>(),
-    );
+    let request = CompletionRequest::default();
+    turns.answering = Some(request);
+    completion_requests.send(request);
 }
 
-#[derive(Event)]
+/// Where a completion request can go: over HTTP, answered in-process, or streamed
+#[derive(SystemParam)]
+struct CompletionSenders<'w> {
+    http: EventWriter<'w, TypedRequest<ChatCompletionResponse>>,
+    ready: EventWriter<'w, ApiResponseEvent>,
+    streaming: Option<Res<'w, StreamingClient>>,
+}
+
+fn send_completion_request(
+    mut game_master: ResMut<GameMaster>,
+    all_messages: Res<AllMessages>,
+    mut completion_requests: EventReader<CompletionRequest>,
+    mut senders: CompletionSenders,
+    tool_calling: Res<ToolCalling>,
+    grid: Res<GridState>,
+) {
+    for _ in completion_requests.read() {
+        let mut request_body = ChatCompletionRequest::new(game_master.0.model(), all_messages.messages.clone());
+        if tool_calling.0 {
+            request_body = request_body.with_tools(&grid);
+        }
+
+        // Tool call deltas aren't parsed incrementally, so tool calling always waits for the full response
+        if let (Some(streaming), Some(endpoint), false) = (&senders.streaming, game_master.0.endpoint(), tool_calling.0) {
+            request_body.stream = true;
+            streaming.send(StreamJob {
+                url: endpoint.url.to_string(),
+                api_key: endpoint.api_key.map(str::to_string),
+                body: serde_json::to_string(&request_body).unwrap(),
+            });
+            continue;
+        }
+
+        match game_master.0.request(&request_body) {
+            BackendReply::Pending(request) => {
+                senders.http.send(request);
+            }
+            BackendReply::Ready(response) => {
+                senders.ready.send(ApiResponseEvent { response, drawn: Drawn::default(), drawn_over: None });
+            }
+        }
+    }
+}
+
+#[derive(Event, Clone)]
 pub struct ChatInputRequest {
-    pub text: String
+    pub text: String,
+    /// Set when someone is waiting for this input to be answered, see `TurnFinished`
+    pub turn: Option<TurnId>,
+}
+
+/// Tells a turn someone is waiting on apart from the others
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TurnId(pub u64);
+
+/// A move someone is waiting on, e.g. from `POST /move`. Played like any other move,
+/// and answered with a `TurnFinished` carrying its turn.
+#[derive(Event)]
+pub struct TrackedMove {
+    pub movement: MovementEvent,
+    pub turn: TurnId,
+}
+
+/// Sent once a response has been applied, or given up on, and the game is waiting for the player again
+#[derive(Event)]
+pub struct TurnFinished {
+    /// The turn that finished, if anyone was waiting on it
+    pub turn: Option<TurnId>,
+    /// Why the turn failed, if it did
+    pub error: Option<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -164,64 +415,468 @@ struct MoveRequest {
     pub move_action: String
 }
 
+#[derive(Serialize)]
+struct ClickRequest<'a> {
+    click_cell: &'a Point,
+}
+
+/// Sends moves to the game master, unless rules are running the game and answer them instead.
+/// Moves are read either way, so none of them reach the game master once the rules stop.
 pub fn chat_writer(
+    rules: Res<Rules>,
     mut event_reader: EventReader<MovementEvent>,
+    mut tracked_moves: EventReader<TrackedMove>,
     mut event_writer: EventWriter<ChatInputRequest>
 ) {
-    for event in event_reader.read() {
-        let text = match event {
-            MovementEvent::Left => "left",
-            MovementEvent::Right => "right",
-            MovementEvent::Up => "up",
-            MovementEvent::Down => "down",
-        };
+    let moves = event_reader.read().map(|movement| (movement, None))
+        .chain(tracked_moves.read().map(|tracked| (&tracked.movement, Some(tracked.turn))))
+        .filter(|_| !rules.active());
+    for (movement, turn) in moves {
         event_writer.send(ChatInputRequest {
             text: serde_json::to_string(&MoveRequest {
-                move_action: text.to_string()
-            }).unwrap()
+                move_action: movement.name().to_string()
+            }).unwrap(),
+            turn,
         });
     }
 }
 
+/// Sends clicked cells to the game master the way `chat_writer` sends moves
+pub fn click_writer(
+    rules: Res<Rules>,
+    mut event_reader: EventReader<CellClickEvent>,
+    mut event_writer: EventWriter<ChatInputRequest>
+) {
+    for event in event_reader.read().filter(|_| !rules.active()) {
+        event_writer.send(ChatInputRequest {
+            text: serde_json::to_string(&ClickRequest { click_cell: &event.point }).unwrap(),
+            turn: None,
+        });
+    }
+}
 
-fn chat_reader(mut all_messages: ResMut<AllMessages>, mut event_reader: EventReader<ChatInputRequest>, mut ev_request: EventWriter<TypedRequest<ChatCompletionResponse>>) {
-    for event in event_reader.read() {
-        let api_key = env::var("GROQ_API_KEY").expect("GROQ_API_KEY must be set");
 
-        all_messages.messages.push(ChatMessage { role: "user".to_string(), content: event.text.clone() });
-        let request_body = ChatCompletionRequest {
-            messages: all_messages.messages.clone(),
-            model: "llama3-70b-8192".to_string(),
-            temperature: 1.0,
-            max_tokens: 1024,
-            top_p: 1.0,
-            stream: false,
-            response_format: ResponseFormat { r#type: "json_object".to_string() },
-            stop: None,
-        };
+/// Keeps the schema bounds and board size in the system prompt in step with the grid
+fn refresh_system_prompt(
+    grid: Res<GridState>,
+    tool_calling: Res<ToolCalling>,
+    mut all_messages: ResMut<AllMessages>,
+    mut prompt_size: Local<Option<(u8, u8)>>,
+) {
+    if *prompt_size == Some((grid.width, grid.height)) || all_messages.messages.is_empty() {
+        return;
+    }
+    *prompt_size = Some((grid.width, grid.height));
+    all_messages.messages[0] = ChatMessage::system_prompt(tool_calling.0, &grid);
+}
+
+/// Hands the next input to the game master once it has answered the last one
+fn chat_reader(
+    grid: Res<GridState>,
+    mut pending_violations: ResMut<PendingViolations>,
+    mut all_messages: ResMut<AllMessages>,
+    mut event_reader: EventReader<ChatInputRequest>,
+    mut turns: ResMut<Turns>,
+    mut completion_requests: EventWriter<CompletionRequest>,
+) {
+    turns.waiting.extend(event_reader.read().cloned());
+    if turns.answering.is_some() {
+        return;
+    }
+    let Some(input) = turns.waiting.pop_front() else {
+        return;
+    };
+
+    // The model works from the real board rather than what it remembers drawing
+    let mut content = format!("{}\nCurrent board: {}", input.text, grid.compact());
+    if !pending_violations.0.is_empty() {
+        let violations = serde_json::to_string(&pending_violations.0).unwrap();
+        content.push_str(&format!("\nPoints and sizes from your last response that did not fit the board: {}", violations));
+        pending_violations.0.clear();
+    }
+    all_messages.messages.push(ChatMessage::user(content));
+    let request = CompletionRequest { turn: input.turn };
+    turns.answering = Some(request);
+    completion_requests.send(request);
+}
+
+#[derive(Default)]
+struct StreamProgress {
+    content: String,
+    parser: PartialUpdateParser,
+    // The board before anything in this response was drawn
+    before: Option<GridState>,
+}
+
+impl StreamProgress {
+    /// The board before this response drew on it, if it has
+    fn drawn_over(self) -> Option<GridState> {
+        let drawn = self.parser.drawn();
+        if drawn.points == 0 && !drawn.cleared {
+            return None;
+        }
+        self.before
+    }
+}
+
+/// Puts the board back the way it was before an unusable response drew on it.
+/// Half a response is worse than none, and the retry starts from the same board.
+fn rollback(before: &GridState) -> SceneUpdate {
+    SceneUpdate::UpdateGame {
+        clear_grid: Some(true),
+        update_points: before.painted().map(|(point, color)| PointColor { color, point }).collect(),
+        game_end: None,
+        message: None,
+        score: None,
+    }
+}
+
+/// The board, and how points from the game master are checked against it
+#[derive(SystemParam)]
+struct BoardValidation<'w> {
+    grid: Res<'w, GridState>,
+    point_validation: Res<'w, PointValidation>,
+}
+
+impl BoardValidation<'_> {
+    fn validator(&self) -> PointValidator {
+        self.point_validation.validator(&self.grid)
+    }
+}
+
+/// How a streamed response ended, for `handle_response` to pick up
+#[derive(SystemParam)]
+struct StreamResults<'w> {
+    ready: EventWriter<'w, ApiResponseEvent>,
+    failed: EventWriter<'w, ResponseFailed>,
+}
+
+/// Draws points as soon as they arrive, then hands the full message to `handle_response`
+fn read_stream(
+    streaming: Res<StreamingClient>,
+    game_master: Res<GameMaster>,
+    board: BoardValidation,
+    mut progress: Local<StreamProgress>,
+    mut prompt: ResMut<Prompt>,
+    mut scene_update_events: EventWriter<SceneUpdate>,
+    mut results: StreamResults,
+) {
+    for event in streaming.events.try_iter() {
+        match event {
+            StreamEvent::Delta(delta) => {
+                if progress.before.is_none() {
+                    progress.before = Some(board.grid.clone());
+                }
+                progress.content.push_str(&delta);
+                prompt.response = progress.content.clone();
+                let StreamProgress { content, parser, .. } = &mut *progress;
+                if let Some(update) = parser.feed(content) {
+                    // Violations are reported once the whole response has been validated
+                    let (update_points, _) = board.validator().validate(&update.points);
+                    scene_update_events.send(SceneUpdate::UpdateGame {
+                        clear_grid: Some(update.clear_grid),
+                        update_points,
+                        game_end: None,
+                        message: None,
+                        score: None,
+                    });
+                }
+            }
+            StreamEvent::Done => {
+                let mut finished = std::mem::take(&mut *progress);
+                let content = std::mem::take(&mut finished.content);
+                results.ready.send(ApiResponseEvent {
+                    response: ChatCompletionResponse::from_content(game_master.0.model(), content),
+                    drawn: finished.parser.drawn(),
+                    drawn_over: finished.drawn_over(),
+                });
+            }
+            StreamEvent::Failed(error) => {
+                let failed = std::mem::take(&mut *progress);
+                if let Some(before) = failed.drawn_over() {
+                    scene_update_events.send(rollback(&before));
+                }
+                results.failed.send(ResponseFailed { error: format!("Error streaming response: {}", error) });
+            }
+        }
+    }
+}
+
+/// How many times the game master gets to fix a response we couldn't use
+#[derive(Resource)]
+struct RepairAttempts {
+    max_retries: u32,
+    used: u32,
+}
+
+impl Default for RepairAttempts {
+    fn default() -> Self {
+        Self {
+            max_retries: env::var("GM_REPAIR_RETRIES").ok().and_then(|retries| retries.parse().ok()).unwrap_or(2),
+            used: 0,
+        }
+    }
+}
 
-        ev_request.send(
-            HttpClient::new()
-                .post("https://api.groq.com/openai/v1/chat/completions")
-                .headers(&[
-                    ("Authorization", &format!("Bearer {}", api_key)),
-                    ("Content-Type", "application/json")
-                ])
-                .json(&request_body)
-                .with_type::<ChatCompletionResponse>(),
-        );
+/// Responses from the HTTP client and from in-process backends, and those that never arrived
+#[derive(SystemParam)]
+struct Responses<'w, 's> {
+    http: EventReader<'w, 's, TypedResponse<ChatCompletionResponse>>,
+    ready: EventReader<'w, 's, ApiResponseEvent>,
+    failed: EventReader<'w, 's, ResponseFailed>,
+    http_errors: EventReader<'w, 's, TypedResponseError<ChatCompletionResponse>>,
+}
+
+/// The conversation so far, and what it takes to ask the game master to try again or to end the turn
+#[derive(SystemParam)]
+struct Conversation<'w> {
+    all_messages: ResMut<'w, AllMessages>,
+    repair_attempts: ResMut<'w, RepairAttempts>,
+    turns: ResMut<'w, Turns>,
+    completion_requests: EventWriter<'w, CompletionRequest>,
+    turns_finished: EventWriter<'w, TurnFinished>,
+    tool_calling: Res<'w, ToolCalling>,
+}
+
+impl Conversation<'_> {
+    /// Asks the game master again, with `repair` explaining what went wrong if there is anything to explain.
+    /// Returns false once the attempts have run out.
+    fn retry(&mut self, repair: Option<ChatMessage>) -> bool {
+        if self.repair_attempts.used >= self.repair_attempts.max_retries {
+            return false;
+        }
+        self.repair_attempts.used += 1;
+        self.all_messages.messages.extend(repair);
+        // Still the same turn, whoever is waiting on it keeps waiting
+        self.completion_requests.send(self.turns.answering.unwrap_or_default());
+        true
+    }
+
+    /// Ends the turn being answered, with what went wrong if it failed
+    fn finish(&mut self, error: Option<String>) {
+        self.repair_attempts.used = 0;
+        let turn = self.turns.answering.take().and_then(|request| request.turn);
+        self.turns_finished.send(TurnFinished { turn, error });
     }
 }
 
 fn handle_response(
     mut prompt: ResMut<Prompt>,
-    mut ev_response: EventReader<TypedResponse<ChatCompletionResponse>>,
+    mut responses: Responses,
     mut scene_update_events: EventWriter<SceneUpdate>,
-    mut all_messages: ResMut<AllMessages>,
+    mut conversation: Conversation,
+    board: BoardValidation,
+    mut pending_violations: ResMut<PendingViolations>,
 ) {
-    for response in ev_response.read() {
-        prompt.response = response.choices[0].message.content.clone();
-        all_messages.messages.push(ChatMessage { role: "assistant".to_string(), content: prompt.response.clone() });
-        handle_request(&mut scene_update_events, &prompt.response);
+    let Responses { http, ready, failed, http_errors } = &mut responses;
+    let responses = http.read().map(|response| (&**response, Drawn::default(), None))
+        .chain(ready.read().map(|ready| (&ready.response, ready.drawn, ready.drawn_over.as_ref())));
+
+    for (response, drawn, drawn_over) in responses {
+        let content = response.content().to_string();
+        let all_messages = &mut conversation.all_messages;
+        let mut validator = board.validator();
+        let (result, sent) = match response.tool_calls() {
+            // Some providers answer a filtered or failed completion with no choices at all
+            _ if response.choices.is_empty() => (Err("The response has no choices".to_string()), content),
+            Some(tool_calls) => {
+                all_messages.messages.push(ChatMessage::assistant(content, Some(tool_calls.clone())));
+                let result = handle_tool_calls(tool_calls, &mut scene_update_events, all_messages, &mut validator);
+                (result, serde_json::to_string(tool_calls).unwrap())
+            }
+            None => {
+                all_messages.messages.push(ChatMessage::assistant(content.clone(), None));
+                (handle_request(&mut scene_update_events, &content, &mut validator, drawn), content)
+            }
+        };
+        prompt.response = sent;
+
+        match result {
+            Ok(applied) => {
+                if board.point_validation.report {
+                    pending_violations.0.extend(applied.violations);
+                }
+                // A resized board is blank, so the game has to be drawn on it before the player can go on
+                if applied.left_blank && conversation.retry(Some(draw_request(validator.size()))) {
+                    continue;
+                }
+                conversation.finish(None);
+            }
+            Err(error) => {
+                println!("Error deserializing: {} -- {}", error, prompt.response);
+                if let Some(before) = drawn_over {
+                    scene_update_events.send(rollback(before));
+                }
+                let repair = ChatMessage::repair_request(&error, &prompt.response, conversation.tool_calling.0);
+                if !conversation.retry(Some(repair)) {
+                    scene_update_events.send(SceneUpdate::Sorry {
+                        error: LOST_TRACK_LINE.to_string(),
+                    });
+                    conversation.finish(Some(error));
+                }
+            }
+        }
+        record_session(&conversation.all_messages);
+    }
+
+    let failures = failed.read().map(|failure| failure.error.clone())
+        .chain(http_errors.read().map(|error| format!("Error requesting a completion: {}", error.as_str())));
+    for error in failures {
+        println!("{}", error);
+        // Nothing came back, so there's nothing to correct, just ask again
+        if !conversation.retry(None) {
+            scene_update_events.send(SceneUpdate::Sorry {
+                error: LOST_TRACK_LINE.to_string(),
+            });
+            conversation.finish(Some(error));
+        }
+    }
+}
+
+/// Applies each tool call and answers it, so the conversation stays valid for the next turn
+fn handle_tool_calls(
+    tool_calls: &[ToolCall],
+    scene_update_events: &mut EventWriter<SceneUpdate>,
+    all_messages: &mut AllMessages,
+    validator: &mut PointValidator,
+) -> Result<Applied, String> {
+    let mut errors = vec![];
+    let mut applied = Applied::default();
+    for tool_call in tool_calls {
+        let outcome = match parse_tool_call(&tool_call.function.name, &tool_call.function.arguments) {
+            Ok(action) => {
+                // Tool calls are never streamed, so nothing has been drawn yet
+                applied.emit(scene_update_events, action, validator, Drawn::default());
+                "ok".to_string()
+            }
+            Err(error) => {
+                errors.push(error.clone());
+                error
+            }
+        };
+        all_messages.messages.push(ChatMessage::tool_result(&tool_call.id, outcome));
+    }
+
+    if errors.is_empty() {
+        Ok(applied)
+    } else {
+        Err(errors.join(". "))
+    }
+}
+
+/// Asks for the game to be drawn on a board that was just resized
+fn draw_request((width, height): (u8, u8)) -> ChatMessage {
+    ChatMessage::user(format!("The board is now {}x{} and blank. Draw the game on it.", width, height))
+}
+
+/// Saves the conversation to `GM_RECORD` so it can be replayed with the mock backend
+fn record_session(all_messages: &AllMessages) {
+    if let Ok(path) = env::var("GM_RECORD") {
+        if let Err(e) = fs::write(&path, serde_json::to_string_pretty(all_messages).unwrap()) {
+            println!("Error recording session to {}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_plugin::RequestAudioEvent;
+    use crate::backend::ScriptedBackend;
+    use crate::{update_map, GameStatus};
+
+    /// Every finished turn, and whether it failed
+    #[derive(Resource, Default)]
+    struct FinishedTurns(Vec<(Option<TurnId>, bool)>);
+
+    fn scripted_app(script: &str) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(GameMaster(Box::new(ScriptedBackend::from_script(script))))
+            .add_plugins(GameMasterPlugin)
+            .init_resource::<Prompt>()
+            .insert_resource(GridState::new(5, 5))
+            .init_resource::<GameStatus>()
+            .init_resource::<FinishedTurns>()
+            .init_resource::<Rules>()
+            .add_event::<RequestAudioEvent>()
+            .add_event::<MovementEvent>()
+            .add_systems(Update, (update_map.after(ResponseSystems), chat_writer))
+            .add_systems(Last, |mut turns: EventReader<TurnFinished>, mut finished: ResMut<FinishedTurns>| {
+                finished.0.extend(turns.read().map(|turn| (turn.turn, turn.error.is_some())));
+            });
+        app
+    }
+
+    fn run_script(script: &str) -> App {
+        let mut app = scripted_app(script);
+        for _ in 0..8 {
+            app.update();
+        }
+        app
+    }
+
+    #[test]
+    fn test_scripted_reply_is_drawn_on_the_board() {
+        let script = r##"{"action":"UpdateGame","value":{"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}}],"message":"Go","score":3}}"##;
+        let app = run_script(script);
+
+        let grid = app.world.resource::<GridState>();
+        assert_eq!(grid.get(&Point { x: 1, y: 2 }), Some(Color::rgb_u8(255, 0, 0)));
+        assert_eq!(grid.get(&Point { x: 2, y: 1 }), Some(Color::WHITE));
+        assert_eq!(app.world.resource::<GameStatus>().score, Some(3));
+        assert_eq!(app.world.resource::<AllMessages>().messages.last().unwrap().role, "assistant");
+    }
+
+    #[test]
+    fn test_resized_board_is_drawn_on_the_next_request() {
+        let script = r##"
+            {"action":"SetGridSize","value":{"width":8,"height":6}}
+            {"action":"UpdateGame","value":{"update_points":[{"hex":"#0000ff","point":{"x":7,"y":5}}]}}
+        "##;
+        let app = run_script(script);
+
+        let grid = app.world.resource::<GridState>();
+        assert_eq!((grid.width, grid.height), (8, 6));
+        assert_eq!(grid.get(&Point { x: 7, y: 5 }), Some(Color::rgb_u8(0, 0, 255)));
+        let messages = &app.world.resource::<AllMessages>().messages;
+        assert!(messages.iter().any(|message| message.content.starts_with("The board is now 8x6")));
+    }
+
+    #[test]
+    fn test_tracked_move_finishes_with_its_turn_even_when_it_fails() {
+        let script = r##"
+            {"action":"UpdateGame","value":{"update_points":[],"message":"Go"}}
+            {"action":"UpdateGame","value":{"update_points":[],"message":"Moved"}}
+            not json
+            still not json
+            nor this
+        "##;
+        let mut app = scripted_app(script);
+        app.update();
+        // Both moves queue up behind the opening turn and are answered one at a time
+        app.world.send_event(TrackedMove { movement: MovementEvent::Left, turn: TurnId(1) });
+        app.world.send_event(TrackedMove { movement: MovementEvent::Right, turn: TurnId(2) });
+        for _ in 0..12 {
+            app.update();
+        }
+
+        let finished = &app.world.resource::<FinishedTurns>().0;
+        assert_eq!(finished, &[(None, false), (Some(TurnId(1)), false), (Some(TurnId(2)), true)]);
+    }
+
+    #[test]
+    fn test_response_without_choices_is_repaired() {
+        let script = r##"{"action":"UpdateGame","value":{"update_points":[],"message":"Go"}}"##;
+        let mut app = scripted_app(script);
+        let response = serde_json::from_value(serde_json::json!({ "choices": [] })).unwrap();
+        app.world.send_event(ApiResponseEvent { response, drawn: Drawn::default(), drawn_over: None });
+        for _ in 0..8 {
+            app.update();
+        }
+
+        let messages = &app.world.resource::<AllMessages>().messages;
+        assert!(messages.iter().any(|message| message.content.contains("The response has no choices")));
+        assert!(messages.iter().all(|message| message.role != "assistant" || !message.content.is_empty()));
     }
 }
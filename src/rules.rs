@@ -0,0 +1,411 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+use serde_json::json;
+use crate::actions::{PlaySound, PointColor, SceneUpdate};
+use crate::grid::{to_hex, GridState};
+use crate::input::CellClickEvent;
+use crate::network::{AllMessages, ChatInputRequest, ChatMessage, TrackedMove, TurnFinished, TurnId};
+use crate::{MovementEvent, Point};
+
+/// Fastest a script can tick, about once a frame
+pub const MIN_TICK_MS: u32 = 16;
+/// Stops a runaway script before it freezes the game
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// Runs the rules script the game master sends with `SetRules` on a fixed timer,
+/// so real-time games don't need a round trip per frame.
+pub struct RulesPlugin;
+
+impl Plugin for RulesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Rules>()
+            .add_systems(Update, (load_rules, run_rules.after(load_rules)).in_set(RulesSystems));
+    }
+}
+
+/// System set to order input handling against the rules
+#[derive(Debug, Clone, Copy, SystemSet, PartialEq, Eq, Hash)]
+pub struct RulesSystems;
+
+/// The rules currently running the game, if the game master handed them over
+#[derive(Resource, Default)]
+pub struct Rules(Option<ActiveRules>);
+
+impl Rules {
+    /// While rules run, they answer the player's input instead of the game master
+    pub fn active(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+struct ActiveRules {
+    engine: Engine,
+    ast: AST,
+    host: Arc<Mutex<Host>>,
+    // What `init` returned, bound to `this` in the other callbacks. `None` until `init` has run.
+    state: Option<Dynamic>,
+    timer: Timer,
+}
+
+impl ActiveRules {
+    fn load(script: &str, tick_ms: u32) -> Result<Self, String> {
+        let host = Arc::new(Mutex::new(Host::new(GridState::new(1, 1))));
+        let engine = rules_engine(&host);
+        let ast = engine.compile(script).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            engine,
+            ast,
+            host,
+            state: None,
+            timer: Timer::new(Duration::from_millis(tick_ms.max(MIN_TICK_MS) as u64), TimerMode::Repeating),
+        })
+    }
+
+    /// Starts recording what the script does, against a copy of the board
+    fn begin(&self, grid: &GridState) {
+        *self.host.lock().unwrap() = Host::new(grid.clone());
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        let state = if self.defines("init") {
+            self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "init", ())
+                .map_err(|e| format!("init failed: {}", e))?
+        } else {
+            Dynamic::UNIT
+        };
+        self.state = Some(if state.is_unit() { Map::new().into() } else { state });
+        Ok(())
+    }
+
+    /// Calls one of the script's callbacks, if it has it
+    fn call(&mut self, name: &str, args: impl FuncArgs) -> Result<(), String> {
+        if !self.defines(name) {
+            return Ok(());
+        }
+        let state = self.state.get_or_insert_with(|| Map::new().into());
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(state);
+        self.engine.call_fn_with_options::<Dynamic>(options, &mut Scope::new(), &self.ast, name, args)
+            .map(|_| ())
+            .map_err(|e| format!("{} failed: {}", name, e))
+    }
+
+    fn defines(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|function| function.name == name)
+    }
+
+    fn game_over(&self) -> bool {
+        self.game_end().is_some()
+    }
+
+    /// Whether the player won, once the script has ended the game
+    fn game_end(&self) -> Option<bool> {
+        self.host.lock().unwrap().game_end
+    }
+
+    /// Everything recorded since `begin`, as scene updates and questions for the game master
+    fn finish(&self) -> (Vec<SceneUpdate>, Vec<String>) {
+        self.host.lock().unwrap().take()
+    }
+}
+
+/// What the script sees of the game, and what it changed during the current frame
+struct Host {
+    grid: GridState,
+    clear_grid: bool,
+    points: Vec<PointColor>,
+    message: Option<String>,
+    game_end: Option<bool>,
+    score: Option<i64>,
+    sounds: Vec<PlaySound>,
+    questions: Vec<String>,
+}
+
+impl Host {
+    fn new(grid: GridState) -> Self {
+        Self {
+            grid,
+            clear_grid: false,
+            points: vec![],
+            message: None,
+            game_end: None,
+            score: None,
+            sounds: vec![],
+            questions: vec![],
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, hex: &str) -> bool {
+        let (Ok(x), Ok(y), Ok(color)) = (u8::try_from(x), u8::try_from(y), Color::hex(hex)) else {
+            return false;
+        };
+        let point = Point { x, y };
+        if !self.grid.set(&point, color) {
+            return false;
+        }
+        self.points.push(PointColor { color, point });
+        true
+    }
+
+    /// The cell's color, or an empty string off the board
+    fn get(&self, x: i64, y: i64) -> String {
+        let (Ok(x), Ok(y)) = (u8::try_from(x), u8::try_from(y)) else {
+            return String::new();
+        };
+        self.grid.get(&Point { x, y }).map(to_hex).unwrap_or_default()
+    }
+
+    fn clear(&mut self) {
+        self.grid.clear();
+        self.clear_grid = true;
+        self.points.clear();
+    }
+
+    fn message(&mut self, text: &str) {
+        match &mut self.message {
+            Some(message) => {
+                message.push(' ');
+                message.push_str(text);
+            }
+            None => self.message = Some(text.to_string()),
+        }
+    }
+
+    fn sound(&mut self, waveform: &str, frequency: f64, duration_ms: i64) -> bool {
+        let sound = json!({ "waveform": waveform, "frequency": frequency, "duration_ms": duration_ms.max(0) });
+        match serde_json::from_value::<PlaySound>(sound) {
+            Ok(sound) => {
+                self.sounds.push(sound);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn take(&mut self) -> (Vec<SceneUpdate>, Vec<String>) {
+        let mut scene_updates: Vec<SceneUpdate> = self.sounds.drain(..).map(SceneUpdate::PlaySound).collect();
+        let changed = self.clear_grid
+            || !self.points.is_empty()
+            || self.message.is_some()
+            || self.game_end.is_some()
+            || self.score.is_some();
+        if changed {
+            scene_updates.push(SceneUpdate::UpdateGame {
+                clear_grid: std::mem::take(&mut self.clear_grid).then_some(true),
+                update_points: std::mem::take(&mut self.points),
+                game_end: self.game_end.take(),
+                message: self.message.take(),
+                score: self.score.take(),
+            });
+        }
+        (scene_updates, std::mem::take(&mut self.questions))
+    }
+}
+
+/// An engine with the functions scripts use to read and draw the board
+fn rules_engine(host: &Arc<Mutex<Host>>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let h = host.clone();
+    engine.register_fn("set", move |x: i64, y: i64, hex: &str| h.lock().unwrap().set(x, y, hex));
+    let h = host.clone();
+    engine.register_fn("get", move |x: i64, y: i64| h.lock().unwrap().get(x, y));
+    let h = host.clone();
+    engine.register_fn("clear", move || h.lock().unwrap().clear());
+    let h = host.clone();
+    engine.register_fn("width", move || h.lock().unwrap().grid.width as i64);
+    let h = host.clone();
+    engine.register_fn("height", move || h.lock().unwrap().grid.height as i64);
+    let h = host.clone();
+    engine.register_fn("message", move |text: &str| h.lock().unwrap().message(text));
+    let h = host.clone();
+    engine.register_fn("game_over", move |won: bool| h.lock().unwrap().game_end = Some(won));
+    let h = host.clone();
+    engine.register_fn("sound", move |waveform: &str, frequency: f64, duration_ms: i64| {
+        h.lock().unwrap().sound(waveform, frequency, duration_ms)
+    });
+    // Rhai doesn't turn integers into floats on its own
+    let h = host.clone();
+    engine.register_fn("sound", move |waveform: &str, frequency: i64, duration_ms: i64| {
+        h.lock().unwrap().sound(waveform, frequency as f64, duration_ms)
+    });
+    let h = host.clone();
+    engine.register_fn("score", move |score: i64| h.lock().unwrap().score = Some(score));
+    let h = host.clone();
+    engine.register_fn("ask", move |text: &str| h.lock().unwrap().questions.push(text.to_string()));
+
+    engine
+}
+
+fn load_rules(
+    mut scene_updates: EventReader<SceneUpdate>,
+    mut rules: ResMut<Rules>,
+    mut chat_input: EventWriter<ChatInputRequest>,
+) {
+    for event in scene_updates.read() {
+        let SceneUpdate::SetRules { script, tick_ms } = event else {
+            continue;
+        };
+        if script.trim().is_empty() {
+            println!("Rules stopped");
+            rules.0 = None;
+            continue;
+        }
+
+        match ActiveRules::load(script, *tick_ms) {
+            Ok(active) => {
+                println!("Rules loaded, ticking every {}ms", tick_ms);
+                rules.0 = Some(active);
+            }
+            Err(e) => {
+                println!("Rules failed to compile: {}", e);
+                rules.0 = None;
+                chat_input.send(ChatInputRequest {
+                    text: format!("Your rules script did not compile: {}. Send SetRules again with a fix.", e),
+                    turn: None,
+                });
+            }
+        }
+    }
+}
+
+/// Everything the player can do that the script gets to handle
+#[derive(SystemParam)]
+struct PlayerInput<'w, 's> {
+    movements: EventReader<'w, 's, MovementEvent>,
+    tracked_moves: EventReader<'w, 's, TrackedMove>,
+    clicks: EventReader<'w, 's, CellClickEvent>,
+}
+
+impl PlayerInput<'_, '_> {
+    /// Every move this frame, with the turn of those someone is waiting on
+    fn moves(&mut self) -> impl Iterator<Item = (&MovementEvent, Option<TurnId>)> {
+        self.movements.read().map(|movement| (movement, None))
+            .chain(self.tracked_moves.read().map(|tracked| (&tracked.movement, Some(tracked.turn))))
+    }
+
+    fn clear(&mut self) {
+        self.movements.clear();
+        self.tracked_moves.clear();
+        self.clicks.clear();
+    }
+}
+
+/// How the script hands the game back to the game master
+#[derive(SystemParam)]
+struct GameMasterLink<'w> {
+    chat_input: EventWriter<'w, ChatInputRequest>,
+    turns_finished: EventWriter<'w, TurnFinished>,
+    all_messages: ResMut<'w, AllMessages>,
+}
+
+fn run_rules(
+    mut rules: ResMut<Rules>,
+    mut input: PlayerInput,
+    mut scene_updates: EventWriter<SceneUpdate>,
+    mut game_master: GameMasterLink,
+    grid: Res<GridState>,
+    time: Res<Time>,
+) {
+    let Some(active) = rules.0.as_mut() else {
+        input.clear();
+        return;
+    };
+
+    active.begin(&grid);
+    let mut result = if active.state.is_none() { active.init() } else { Ok(()) };
+    let mut played = false;
+    // Moves someone is waiting on are answered even if the game ended before they could be played
+    let mut tracked_turns = vec![];
+    for (movement, turn) in input.moves() {
+        tracked_turns.extend(turn);
+        if result.is_err() || active.game_over() {
+            continue;
+        }
+        played |= turn.is_none();
+        result = active.call("on_input", (movement.name().to_string(),));
+    }
+    for click in input.clicks.read() {
+        if result.is_err() || active.game_over() {
+            break;
+        }
+        played = true;
+        result = active.call("on_click", (click.point.x as i64, click.point.y as i64));
+    }
+
+    active.timer.tick(time.delta());
+    for _ in 0..active.timer.times_finished_this_tick() {
+        if result.is_err() || active.game_over() {
+            break;
+        }
+        result = active.call("tick", ());
+    }
+
+    let game_end = active.game_end();
+    let (updates, questions) = active.finish();
+    for scene_update in updates {
+        scene_updates.send(scene_update);
+    }
+    for text in questions {
+        game_master.chat_input.send(ChatInputRequest { text, turn: None });
+    }
+    // The script answers moves right away, there's no response to wait for
+    let error = result.as_ref().err().map(|e| format!("The rules script stopped with an error: {}", e));
+    for turn in tracked_turns {
+        game_master.turns_finished.send(TurnFinished { turn: Some(turn), error: error.clone() });
+    }
+    if played {
+        game_master.turns_finished.send(TurnFinished { turn: None, error: error.clone() });
+    }
+
+    if let Err(e) = result {
+        println!("Rules stopped: {}", e);
+        rules.0 = None;
+        game_master.chat_input.send(ChatInputRequest {
+            text: format!("Your rules script stopped with an error: {}. Send SetRules again with a fix, or run the game yourself.", e),
+            turn: None,
+        });
+    } else if let Some(won) = game_end {
+        println!("Rules finished the game");
+        rules.0 = None;
+        // The game master is back in charge and should know how its game went
+        let outcome = if won { "won" } else { "lost" };
+        game_master.all_messages.messages.push(ChatMessage::user(format!("The rules ended the game: the player {}.", outcome)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_draw_and_end_the_game() {
+        let script = r##"
+            fn init() { set(0, 0, "#ff0000"); #{ x: 0 } }
+            fn tick() { this.x += 1; set(this.x, 0, "#ff0000"); sound("square", 880, 50); }
+            fn on_input(action) { if action == "left" && get(this.x, 0) == "#ff0000" { game_over(false); } }
+        "##;
+        let mut rules = ActiveRules::load(script, 100).unwrap();
+
+        rules.begin(&GridState::new(5, 5));
+        rules.init().unwrap();
+        rules.call("tick", ()).unwrap();
+        let (updates, _) = rules.finish();
+        assert!(matches!(
+            updates.as_slice(),
+            [SceneUpdate::PlaySound(_), SceneUpdate::UpdateGame { update_points, game_end: None, .. }] if update_points.len() == 2
+        ));
+
+        let mut grid = GridState::new(5, 5);
+        grid.set(&Point { x: 1, y: 0 }, Color::rgb_u8(255, 0, 0));
+        rules.begin(&grid);
+        rules.call("on_input", ("left".to_string(),)).unwrap();
+        assert!(rules.game_over());
+
+        assert!(ActiveRules::load("fn tick( {", 100).is_err());
+    }
+}
@@ -1,11 +1,44 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use bevy::prelude::*;
+use futures_util::stream;
 use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, oneshot};
+use warp::http::StatusCode;
+use warp::sse::Event;
 use warp::Filter;
-use crate::network::ChatInputRequest;
+use crate::actions::SceneUpdate;
+use crate::grid::GridState;
+use crate::input::CustomActions;
+use crate::network::{AllMessages, ChatInputRequest, ChatMessage, TrackedMove, TurnFinished, TurnId};
+use crate::{GameStatus, MovementEvent};
+
+/// The browser client, a page that plays along over `GET /events` and `POST /move`
+const INDEX_HTML: &str = include_str!("../web/index.html");
+
+/// How long `POST /move` waits for the game master before giving up
+const MOVE_TIMEOUT: Duration = Duration::from_secs(120);
 
 #[derive(Resource)]
 struct CrossbeamReceiver(pub crossbeam_channel::Receiver<String>);
 
+/// Scene updates as JSON, on their way out to every client on `GET /events`
+#[derive(Resource)]
+struct SceneBroadcast(broadcast::Sender<String>);
+
+/// The latest game state and transcript, copied out of the world for the API to read
+#[derive(Resource, Clone, Default)]
+struct Snapshot(Arc<RwLock<SnapshotData>>);
+
+#[derive(Default)]
+struct SnapshotData {
+    state: Value,
+    history: Vec<ChatMessage>,
+}
+
 pub struct ServerPlugin;
 
 #[derive(Deserialize)]
@@ -13,22 +46,98 @@ struct Message {
     text: String,
 }
 
+#[derive(Deserialize)]
+struct MoveInput {
+    /// A direction, "action", "confirm", "cancel" or a declared action's name
+    move_action: String,
+}
+
+/// A move from `POST /move`, answered with a status and body once the game has played it
+struct MoveJob {
+    movement: MovementEvent,
+    reply: oneshot::Sender<(StatusCode, Value)>,
+}
+
+#[derive(Resource)]
+struct MoveReceiver(crossbeam_channel::Receiver<MoveJob>);
+
+/// Moves from the API are played one at a time, so each reply only holds what its own move did
+#[derive(Resource, Default)]
+struct MoveQueue {
+    waiting: VecDeque<MoveJob>,
+    playing: Option<PlayingMove>,
+    next_turn: u64,
+}
+
+struct PlayingMove {
+    turn: TurnId,
+    reply: oneshot::Sender<(StatusCode, Value)>,
+    diff: Vec<Value>,
+    narration: Vec<String>,
+    /// Set once the turn is over, to what went wrong if it failed
+    finished: Option<Option<String>>,
+}
+
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
         // Create a channel to send requests from the API to the Bevy app
         let (sender, receiver) = crossbeam_channel::unbounded();
+        let (move_sender, move_receiver) = crossbeam_channel::unbounded();
+        let snapshot = Snapshot::default();
+        let api_snapshot = snapshot.clone();
+        // Clients that fall this far behind skip ahead rather than hold the game up
+        let (scene_sender, _) = broadcast::channel::<String>(256);
+        let api_scene_sender = scene_sender.clone();
 
         // Spawn a new thread to run the API server
         std::thread::spawn(move || {
-            let routes = warp::post()
+            let chat = warp::post()
                 .and(warp::path("chat"))
                 .and(warp::body::json())
                 .map(move |input: Message| {
                     sender.send(input.text).expect("Failed to send chat input");
-                    warp::reply::json(&serde_json::json!({ "status": "ok" }))
+                    warp::reply::json(&json!({ "status": "ok" }))
+                });
+
+            let moves = warp::post()
+                .and(warp::path("move"))
+                .and(warp::path::end())
+                .and(warp::body::json())
+                .and_then(move |input: MoveInput| {
+                    let move_sender = move_sender.clone();
+                    async move {
+                        let (reply, outcome) = oneshot::channel();
+                        let movement = MovementEvent::from_name(&input.move_action);
+                        let _ = move_sender.send(MoveJob { movement, reply });
+
+                        let (status, body) = match tokio::time::timeout(MOVE_TIMEOUT, outcome).await {
+                            Ok(Ok(answer)) => answer,
+                            Ok(Err(_)) | Err(_) => (StatusCode::GATEWAY_TIMEOUT, json!({ "error": "The game didn't finish the move in time" })),
+                        };
+                        Ok::<_, Infallible>(warp::reply::with_status(warp::reply::json(&body), status))
+                    }
+                });
+
+            let state_snapshot = api_snapshot.clone();
+            let state = warp::get()
+                .and(warp::path("state"))
+                .and(warp::path::end())
+                .map(move || warp::reply::json(&state_snapshot.0.read().unwrap().state));
+
+            let history = warp::get()
+                .and(warp::path("history"))
+                .and(warp::path::end())
+                .map(move || warp::reply::json(&api_snapshot.0.read().unwrap().history));
+
+            let events = warp::get()
+                .and(warp::path("events"))
+                .and(warp::path::end())
+                .map(move || {
+                    let updates = scene_stream(api_scene_sender.subscribe());
+                    warp::sse::reply(warp::sse::keep_alive().stream(updates))
                 });
 
-            let server = warp::serve(routes).run(([0, 0, 0, 0], 3030));
+            let server = warp::serve(index().or(chat).or(moves).or(state).or(history).or(events)).run(([0, 0, 0, 0], 3030));
 
             // Use tokio to spawn the server future
             tokio::runtime::Builder::new_multi_thread()
@@ -40,7 +149,12 @@ impl Plugin for ServerPlugin {
 
         // Add a system to handle the chat input requests from the API
         app.insert_resource(CrossbeamReceiver(receiver))
-            .add_systems(Update, handle_chat_input_requests);
+            .insert_resource(MoveReceiver(move_receiver))
+            .init_resource::<MoveQueue>()
+            .insert_resource(snapshot)
+            .insert_resource(SceneBroadcast(scene_sender))
+            .add_systems(Update, (handle_chat_input_requests, play_moves, broadcast_scene_updates))
+            .add_systems(Last, (finish_moves, publish_snapshot));
     }
 }
 
@@ -49,6 +163,202 @@ fn handle_chat_input_requests(
     receiver: Res<CrossbeamReceiver>
 ) {
     for input in receiver.0.try_iter() {
-        chat_input_events.send(ChatInputRequest { text: input });
+        chat_input_events.send(ChatInputRequest { text: input, turn: None });
+    }
+}
+
+/// Starts the next move from the API once the last one has been answered
+fn play_moves(
+    receiver: Res<MoveReceiver>,
+    custom_actions: Res<CustomActions>,
+    mut queue: ResMut<MoveQueue>,
+    mut movements: EventWriter<TrackedMove>,
+) {
+    queue.waiting.extend(receiver.0.try_iter());
+    // Nobody is waiting on it any more, e.g. because it timed out
+    if queue.playing.as_ref().is_some_and(|playing| playing.reply.is_closed()) {
+        queue.playing = None;
+    }
+
+    while queue.playing.is_none() {
+        let Some(job) = queue.waiting.pop_front() else {
+            return;
+        };
+        if let MovementEvent::Custom(name) = &job.movement {
+            if !custom_actions.actions.iter().any(|action| &action.name == name) {
+                let _ = job.reply.send((StatusCode::BAD_REQUEST, json!({ "error": format!("Unknown move: {}", name) })));
+                continue;
+            }
+        }
+        queue.next_turn += 1;
+        let turn = TurnId(queue.next_turn);
+        movements.send(TrackedMove { movement: job.movement, turn });
+        queue.playing = Some(PlayingMove { turn, reply: job.reply, diff: vec![], narration: vec![], finished: None });
+    }
+}
+
+/// Collects what the move did until the turn is over, then answers with it and the board
+fn finish_moves(
+    mut queue: ResMut<MoveQueue>,
+    mut scene_updates: EventReader<SceneUpdate>,
+    mut turns_finished: EventReader<TurnFinished>,
+    grid: Res<GridState>,
+    status: Res<GameStatus>,
+    custom_actions: Res<CustomActions>,
+) {
+    let Some(playing) = queue.playing.as_mut() else {
+        turns_finished.clear();
+        scene_updates.clear();
+        return;
+    };
+
+    // The turn's updates are on the board by the frame after it finished
+    if let Some(error) = playing.finished.take() {
+        turns_finished.clear();
+        scene_updates.clear();
+        let playing = queue.playing.take().unwrap();
+        let mut body = json!({
+            "diff": playing.diff,
+            "narration": playing.narration,
+            "state": state_json(&grid, &status, &custom_actions),
+        });
+        let status = match error {
+            Some(error) => {
+                body["error"] = json!(error);
+                StatusCode::BAD_GATEWAY
+            }
+            None => StatusCode::OK,
+        };
+        let _ = playing.reply.send((status, body));
+        return;
+    }
+
+    let mut others_finished = false;
+    for finished in turns_finished.read() {
+        if finished.turn == Some(playing.turn) {
+            playing.finished = Some(finished.error.clone());
+        } else {
+            others_finished = true;
+        }
+    }
+    // The game master answers one turn at a time, so until ours is done everything so far answered someone else
+    if others_finished && playing.finished.is_none() {
+        playing.diff.clear();
+        playing.narration.clear();
+        scene_updates.clear();
+        return;
+    }
+
+    for update in scene_updates.read() {
+        match update {
+            SceneUpdate::UpdateGame { message: Some(message), .. } => playing.narration.push(message.clone()),
+            SceneUpdate::Sorry { error } => playing.narration.push(error.clone()),
+            _ => {}
+        }
+        match serde_json::to_value(update) {
+            Ok(update) => playing.diff.push(update),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
+
+fn broadcast_scene_updates(mut scene_updates: EventReader<SceneUpdate>, broadcast: Res<SceneBroadcast>) {
+    for update in scene_updates.read() {
+        match serde_json::to_string(update) {
+            // Only fails when nobody is listening
+            Ok(json) => { let _ = broadcast.0.send(json); }
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+}
+
+/// `GET /`, the browser client
+fn index() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path::end())
+        .map(|| warp::reply::html(INDEX_HTML))
+}
+
+/// One server-sent event per scene update, until the game shuts down
+fn scene_stream(receiver: broadcast::Receiver<String>) -> impl futures_util::Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(json) => return Some((Ok(Event::default().data(json)), receiver)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => println!("Event stream skipped {} updates", skipped),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// What `GET /state` returns
+fn state_json(grid: &GridState, status: &GameStatus, custom_actions: &CustomActions) -> Value {
+    json!({
+        "board": grid.to_json(),
+        "message": status.message,
+        "score": status.score,
+        "game_over": status.game_end.is_some(),
+        "won": status.game_end,
+        // So a client that connects after `declare_actions` still knows the keys
+        "actions": custom_actions.actions,
+    })
+}
+
+fn publish_snapshot(
+    grid: Res<GridState>,
+    status: Res<GameStatus>,
+    custom_actions: Res<CustomActions>,
+    all_messages: Res<AllMessages>,
+    snapshot: Res<Snapshot>,
+) {
+    if snapshot.is_added() || grid.is_changed() || status.is_changed() || custom_actions.is_changed() {
+        snapshot.0.write().unwrap().state = state_json(&grid, &status, &custom_actions);
+    }
+    if snapshot.is_added() || all_messages.is_changed() {
+        // The system prompt is ours, not part of the conversation
+        snapshot.0.write().unwrap().history = all_messages.messages
+            .iter()
+            .filter(|message| message.role != "system")
+            .cloned()
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::prelude::Color;
+    use crate::actions::CustomAction;
+    use crate::Point;
+
+    #[test]
+    fn test_state_json_reports_board_and_status() {
+        let mut grid = GridState::new(3, 2);
+        grid.set(&Point { x: 2, y: 1 }, Color::rgb(1.0, 0.0, 0.0));
+        let status = GameStatus { message: Some("Nice".to_string()), score: Some(12), game_end: Some(true) };
+
+        let mut custom_actions = CustomActions::default();
+        custom_actions.actions.push(CustomAction { name: "fire".to_string(), key: Some("KeyF".to_string()), description: None });
+
+        let state = state_json(&grid, &status, &custom_actions);
+        assert_eq!(state["board"]["width"], 3);
+        assert_eq!(state["board"]["cells"]["#ff0000"], json!([[2, 1]]));
+        assert_eq!(state["message"], "Nice");
+        assert_eq!(state["score"], 12);
+        assert_eq!(state["game_over"], true);
+        assert_eq!(state["won"], true);
+        assert_eq!(state["actions"][0]["key"], "KeyF");
+    }
+
+    #[test]
+    fn test_index_serves_the_browser_client() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let response = runtime.block_on(warp::test::request().path("/").reply(&index()));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+        assert!(String::from_utf8_lossy(response.body()).contains(r#"new EventSource("/events")"#));
+        assert!(!runtime.block_on(warp::test::request().path("/state").matches(&index())));
     }
 }
@@ -0,0 +1,132 @@
+use std::f32::consts::TAU;
+use std::thread;
+use bevy::prelude::*;
+use crossbeam_channel::Sender;
+use rand::Rng;
+use rodio::buffer::SamplesBuffer;
+use rodio::OutputStream;
+use crate::actions::{Envelope, PlaySound, SceneUpdate, Waveform};
+
+const SAMPLE_RATE: u32 = 44_100;
+// What a sound may ask for, whether it comes from the game master or a rules script
+pub const MIN_FREQUENCY: f32 = 20.0;
+pub const MAX_FREQUENCY: f32 = 20_000.0;
+pub const MIN_DURATION_MS: u32 = 10;
+pub const MAX_DURATION_MS: u32 = 5000;
+
+/// Synthesizes the game master's sound effects and plays them over the narration
+pub struct SoundPlugin;
+
+impl Plugin for SoundPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SoundEffects::spawn())
+            .add_systems(Update, play_sounds);
+    }
+}
+
+/// Plays effects on a worker thread. Unlike narration they don't queue, they mix.
+#[derive(Resource)]
+pub struct SoundEffects {
+    sounds: Sender<Vec<f32>>,
+}
+
+impl SoundEffects {
+    pub fn spawn() -> Self {
+        let (sounds, sound_receiver) = crossbeam_channel::unbounded::<Vec<f32>>();
+
+        thread::spawn(move || {
+            // The output stream has to stay on the thread that opened it
+            let (_stream, handle) = match OutputStream::try_default() {
+                Ok(output) => output,
+                Err(e) => {
+                    println!("No audio output, sound effects are off: {}", e);
+                    return;
+                }
+            };
+
+            for samples in sound_receiver.iter() {
+                if let Err(e) = handle.play_raw(SamplesBuffer::new(1, SAMPLE_RATE, samples)) {
+                    eprintln!("Error: {}", e);
+                }
+            }
+        });
+
+        Self { sounds }
+    }
+}
+
+fn play_sounds(mut scene_updates: EventReader<SceneUpdate>, effects: Res<SoundEffects>) {
+    for event in scene_updates.read() {
+        if let SceneUpdate::PlaySound(sound) = event {
+            let _ = effects.sounds.send(synthesize(sound));
+        }
+    }
+}
+
+/// Mono samples for the sound, shaped by its envelope
+fn synthesize(sound: &PlaySound) -> Vec<f32> {
+    let duration_ms = sound.duration_ms.clamp(MIN_DURATION_MS, MAX_DURATION_MS);
+    let frequency = sound.frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY);
+    let length = (duration_ms as u64 * SAMPLE_RATE as u64 / 1000) as usize;
+    let envelope = sound.envelope.clone().unwrap_or_default();
+    let volume = sound.volume.unwrap_or(0.5).clamp(0.0, 1.0);
+    let duration = duration_ms as f32 / 1000.0;
+    let mut rng = rand::thread_rng();
+
+    (0..length).map(|i| {
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let phase = (t * frequency).fract();
+        let wave = match sound.waveform {
+            Waveform::Sine => (phase * TAU).sin(),
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0,
+            Waveform::Noise => rng.gen_range(-1.0..=1.0),
+        };
+        wave * gain(&envelope, t, duration) * volume
+    }).collect()
+}
+
+/// Attack, decay and sustain from the start, release up to the end
+fn gain(envelope: &Envelope, t: f32, duration: f32) -> f32 {
+    let attack = envelope.attack_ms as f32 / 1000.0;
+    let decay = envelope.decay_ms as f32 / 1000.0;
+    let release = envelope.release_ms as f32 / 1000.0;
+    let sustain = envelope.sustain.clamp(0.0, 1.0);
+
+    let level = if t < attack {
+        t / attack
+    } else if t < attack + decay {
+        1.0 - (1.0 - sustain) * (t - attack) / decay
+    } else {
+        sustain
+    };
+    let until_end = duration - t;
+    if until_end < release {
+        level * until_end / release
+    } else {
+        level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_synthesize_follows_duration_and_envelope() {
+        let sound: PlaySound = serde_json::from_str(
+            r#"{"waveform":"square","frequency":440,"duration_ms":100,"envelope":{"attack_ms":10,"decay_ms":0,"sustain":1,"release_ms":20},"volume":0.5}"#
+        ).unwrap();
+        let samples = synthesize(&sound);
+
+        assert_eq!(samples.len(), 4410);
+        assert_eq!(samples[0], 0.0);
+        assert!(samples.iter().all(|sample| sample.abs() <= 0.5));
+        assert_eq!(samples[2205].abs(), 0.5);
+        assert!(samples[4409].abs() < 0.01);
+
+        let endless = PlaySound { duration_ms: u32::MAX, frequency: f32::INFINITY, ..sound };
+        assert_eq!(synthesize(&endless).len(), (MAX_DURATION_MS * SAMPLE_RATE / 1000) as usize);
+    }
+}
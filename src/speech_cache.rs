@@ -0,0 +1,169 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::env;
+use crate::tts::TtsProvider;
+
+/// Synthesized clips kept on disk, keyed by provider, voice and text, so stock lines are only paid for once.
+/// The least recently played clips are deleted once the cache grows past its limit.
+pub struct SpeechCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl SpeechCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    /// `GM_TTS_CACHE` picks the directory (`off` turns the cache off) and `GM_TTS_CACHE_MB` the limit, 50MB by default
+    pub fn from_env() -> Option<Self> {
+        let dir = match env::var("GM_TTS_CACHE") {
+            Ok(dir) if dir == "off" => return None,
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => env::temp_dir().join("gm-tts-cache"),
+        };
+        let max_mb = env::var("GM_TTS_CACHE_MB").ok().and_then(|mb| mb.parse().ok()).unwrap_or(50);
+        Some(Self::new(dir, max_mb * 1024 * 1024))
+    }
+
+    fn path(&self, provider: &str, voice: &str, text: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.audio", stable_hash(&[provider, voice, text])))
+    }
+
+    pub fn get(&self, provider: &str, voice: &str, text: &str) -> Option<Vec<u8>> {
+        let path = self.path(provider, voice, text);
+        let audio = fs::read(&path).ok()?;
+        // Played again, so it's the last to go
+        if let Ok(file) = File::options().append(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(audio)
+    }
+
+    pub fn put(&self, provider: &str, voice: &str, text: &str, audio: &[u8]) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| format!("Failed to create {}: {}", self.dir.display(), e))?;
+        let path = self.path(provider, voice, text);
+        fs::write(&path, audio).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        self.evict(&path);
+        Ok(())
+    }
+
+    /// Deletes the least recently played clips until the cache fits its limit, sparing the one just written
+    fn evict(&self, written: &Path) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut clips: Vec<(SystemTime, u64, PathBuf)> = entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|extension| extension == "audio"))
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+        clips.sort();
+
+        let mut total: u64 = clips.iter().map(|(_, size, _)| size).sum();
+        for (_, size, path) in clips {
+            if total <= self.max_bytes {
+                break;
+            }
+            if path != written && fs::remove_file(&path).is_ok() {
+                total -= size;
+            }
+        }
+    }
+}
+
+/// FNV-1a, so file names stay the same across builds and Rust versions
+fn stable_hash(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+/// Serves clips from the cache and only asks the provider for text it hasn't said before
+pub struct CachedTts {
+    provider: Box<dyn TtsProvider>,
+    cache: SpeechCache,
+}
+
+impl CachedTts {
+    pub fn new(provider: Box<dyn TtsProvider>, cache: SpeechCache) -> Self {
+        Self { provider, cache }
+    }
+}
+
+impl TtsProvider for CachedTts {
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+
+    fn voice(&self) -> String {
+        self.provider.voice()
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String> {
+        let (name, voice) = (self.provider.name(), self.provider.voice());
+        if let Some(audio) = self.cache.get(name, &voice, text) {
+            return Ok(Some(audio));
+        }
+
+        let audio = self.provider.synthesize(text)?;
+        if let Some(audio) = &audio {
+            if let Err(e) = self.cache.put(name, &voice, text, audio) {
+                println!("Speech cache: {}", e);
+            }
+        }
+        Ok(audio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingTts(Arc<AtomicUsize>);
+
+    impl TtsProvider for CountingTts {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        fn voice(&self) -> String {
+            String::new()
+        }
+
+        fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(text.repeat(10).into_bytes()))
+        }
+    }
+
+    #[test]
+    fn test_cached_tts_reuses_clips_and_stays_under_limit() {
+        let dir = env::temp_dir().join(format!("gm-tts-cache-test-{}", std::process::id()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let tts = CachedTts::new(Box::new(CountingTts(calls.clone())), SpeechCache::new(dir.clone(), 250));
+
+        assert_eq!(tts.synthesize("Game over man, game over.").unwrap().unwrap().len(), 250);
+        assert_eq!(tts.synthesize("Game over man, game over.").unwrap().unwrap().len(), 250);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tts.synthesize("Everyone wins sometimes.").unwrap();
+        let clips = fs::read_dir(&dir).unwrap().count();
+        // The clip that went over the limit is the one that stays
+        tts.synthesize("Everyone wins sometimes.").unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(clips, 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
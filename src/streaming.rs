@@ -0,0 +1,292 @@
+use std::thread;
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use crate::actions::PointHex;
+
+/// A streamed completion to run on the worker thread
+pub struct StreamJob {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub body: String,
+}
+
+pub enum StreamEvent {
+    /// The next piece of the assistant message
+    Delta(String),
+    Done,
+    Failed(String),
+}
+
+/// Runs streamed chat completions off the main thread and hands the SSE deltas back to Bevy
+#[derive(Resource)]
+pub struct StreamingClient {
+    jobs: Sender<StreamJob>,
+    pub(crate) events: Receiver<StreamEvent>,
+}
+
+impl StreamingClient {
+    pub fn spawn() -> Self {
+        let (jobs, job_receiver) = crossbeam_channel::unbounded::<StreamJob>();
+        let (event_sender, events) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build tokio runtime");
+            let client = reqwest::Client::new();
+
+            for job in job_receiver.iter() {
+                let result = runtime.block_on(stream_completion(&client, job, &event_sender));
+                let _ = event_sender.send(match result {
+                    Ok(()) => StreamEvent::Done,
+                    Err(e) => StreamEvent::Failed(e.to_string()),
+                });
+            }
+        });
+
+        Self { jobs, events }
+    }
+
+    pub fn send(&self, job: StreamJob) {
+        self.jobs.send(job).expect("Streaming worker has stopped");
+    }
+}
+
+async fn stream_completion(client: &reqwest::Client, job: StreamJob, events: &Sender<StreamEvent>) -> Result<(), reqwest::Error> {
+    let mut request = client.post(&job.url)
+        .header("Content-Type", "application/json")
+        .body(job.body);
+    if let Some(api_key) = &job.api_key {
+        request = request.bearer_auth(api_key);
+    }
+
+    let mut response = request.send().await?.error_for_status()?;
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            match parse_sse_line(&String::from_utf8_lossy(&line)) {
+                Some(SseLine::Delta(delta)) => {
+                    let _ = events.send(StreamEvent::Delta(delta));
+                }
+                Some(SseLine::Done) => return Ok(()),
+                None => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+enum SseLine {
+    Delta(String),
+    Done,
+}
+
+fn parse_sse_line(line: &str) -> Option<SseLine> {
+    let data = line.trim().strip_prefix("data:")?.trim();
+    if data == "[DONE]" {
+        return Some(SseLine::Done);
+    }
+    let chunk: serde_json::Value = serde_json::from_str(data).ok()?;
+    chunk["choices"][0]["delta"]["content"]
+        .as_str()
+        .map(|content| SseLine::Delta(content.to_string()))
+}
+
+/// Points that can be drawn before the rest of the response arrives
+pub struct PartialUpdate {
+    pub clear_grid: bool,
+    pub points: Vec<PointHex>,
+}
+
+/// How much of a streamed `UpdateGame` is on the board already: the first `points` of its
+/// `update_points`, and the clear if it asked for one
+#[derive(Default, Clone, Copy)]
+pub struct Drawn {
+    pub points: usize,
+    pub cleared: bool,
+}
+
+/// Pulls finished `PointHex` objects out of an `UpdateGame` response that is still arriving,
+/// so they can be drawn before the rest of the message is in.
+#[derive(Default)]
+pub struct PartialUpdateParser {
+    emitted: usize,
+    cleared: bool,
+}
+
+impl PartialUpdateParser {
+    /// Looks at everything received so far and returns the points completed since the last call
+    pub fn feed(&mut self, content: &str) -> Option<PartialUpdate> {
+        let value = action_value(content)?;
+        // A clear wipes whatever was drawn before it, so nothing is drawn until it's known whether one is coming
+        let clear_grid = !self.cleared && clear_grid_requested(value)?;
+
+        let points = complete_array_objects(value, "update_points")
+            .into_iter()
+            .skip(self.emitted)
+            .map_while(|object| serde_json::from_str::<PointHex>(object).ok())
+            .collect::<Vec<_>>();
+
+        if points.is_empty() && !clear_grid {
+            return None;
+        }
+        self.emitted += points.len();
+        self.cleared |= clear_grid;
+
+        Some(PartialUpdate { clear_grid, points })
+    }
+
+    pub fn drawn(&self) -> Drawn {
+        Drawn { points: self.emitted, cleared: self.cleared }
+    }
+}
+
+/// The (possibly unfinished) `value` object of an action
+fn action_value(content: &str) -> Option<&str> {
+    let start = content.find("\"value\"")? + "\"value\"".len();
+    content[start..].trim_start().strip_prefix(':')
+}
+
+/// Whether the action asks for the board to be cleared, once that's known: its `clear_grid` has
+/// arrived, or the whole `value` has without one
+fn clear_grid_requested(value: &str) -> Option<bool> {
+    let Some(start) = value.find("\"clear_grid\"") else {
+        return object_closed(value).then_some(false);
+    };
+    let rest = value[start + "\"clear_grid\"".len()..].trim_start().strip_prefix(':')?.trim_start();
+    if rest.starts_with("true") {
+        Some(true)
+    } else if rest.starts_with("false") || rest.starts_with("null") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Whether the object at the start of `value` has been received in full
+fn object_closed(value: &str) -> bool {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in value.chars() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Every fully received object inside the array at `key`
+fn complete_array_objects<'a>(value: &'a str, key: &str) -> Vec<&'a str> {
+    let quoted_key = format!("\"{}\"", key);
+    let Some(key_start) = value.find(&quoted_key) else {
+        return vec![];
+    };
+    let after_key = &value[key_start + quoted_key.len()..];
+    let Some(array_start) = after_key.find('[') else {
+        return vec![];
+    };
+    let array = &after_key[array_start + 1..];
+
+    let mut objects = vec![];
+    let mut depth = 0;
+    let mut object_start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in array.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => {
+                if depth == 0 {
+                    object_start = i;
+                }
+                depth += 1;
+            }
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    objects.push(&array[object_start..=i]);
+                }
+            }
+            ']' if depth == 0 => break,
+            _ => {}
+        }
+    }
+    objects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_count(update: Option<PartialUpdate>) -> usize {
+        update.map_or(0, |update| update.points.len())
+    }
+
+    #[test]
+    fn test_partial_parser_emits_points_as_they_complete() {
+        let full = r##"{"action":"UpdateGame","value":{"clear_grid":true,"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}},{"hex":"#00ff00","point":{"x":3,"y":4}}],"message":"go"}}"##;
+        let cleared = full.find("true").unwrap() + "true".len();
+        let first_done = full.find("}},").unwrap() + 2;
+
+        let mut parser = PartialUpdateParser::default();
+        assert!(parser.feed(&full[..cleared]).is_some_and(|update| update.clear_grid));
+        assert_eq!(point_count(parser.feed(&full[..first_done - 1])), 0);
+        assert_eq!(point_count(parser.feed(&full[..first_done])), 1);
+        assert_eq!(point_count(parser.feed(&full[..first_done + 5])), 0);
+        assert_eq!(point_count(parser.feed(full)), 1);
+        let drawn = parser.drawn();
+        assert!(drawn.cleared && drawn.points == 2);
+    }
+
+    #[test]
+    fn test_partial_parser_waits_for_a_late_clear() {
+        let full = r##"{"action":"UpdateGame","value":{"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}}],"clear_grid":true}}"##;
+        let before_clear = full.find(",\"clear_grid\"").unwrap();
+
+        let mut parser = PartialUpdateParser::default();
+        assert!(parser.feed(&full[..before_clear]).is_none());
+        let update = parser.feed(full).unwrap();
+        assert!(update.clear_grid && update.points.len() == 1);
+
+        let without_clear = r##"{"action":"UpdateGame","value":{"update_points":[{"hex":"#ff0000","point":{"x":1,"y":2}}]}}"##;
+        assert_eq!(point_count(PartialUpdateParser::default().feed(without_clear)), 1);
+    }
+
+    #[test]
+    fn test_parse_sse_line() {
+        let line = r#"data: {"choices":[{"index":0,"delta":{"content":"{\"act"}}]}"#;
+        assert!(matches!(parse_sse_line(line), Some(SseLine::Delta(delta)) if delta == "{\"act"));
+        assert!(matches!(parse_sse_line("data: [DONE]"), Some(SseLine::Done)));
+        assert!(parse_sse_line(": keep-alive").is_none());
+    }
+}
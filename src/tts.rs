@@ -0,0 +1,270 @@
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use crate::speech_cache::{CachedTts, SpeechCache};
+
+/// Turns narration into speech.
+///
+/// Providers run on the narration worker thread, so they are free to block.
+pub trait TtsProvider: Send + 'static {
+    /// Human readable provider name, used for logging.
+    fn name(&self) -> &str;
+
+    /// Everything besides the text that changes how a line sounds, so cached clips are told apart.
+    fn voice(&self) -> String;
+
+    /// Audio in any format rodio can decode, or `None` when there is nothing to play.
+    fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// Voice settings every provider understands, from `GM_TTS_VOICE` and `GM_TTS_SPEED`.
+pub struct VoiceSettings {
+    /// Provider specific voice name, `None` for the provider's default.
+    pub voice: Option<String>,
+    /// 1.0 is normal speed.
+    pub speed: f32,
+}
+
+impl VoiceSettings {
+    pub fn from_env() -> Self {
+        Self {
+            voice: env::var("GM_TTS_VOICE").ok(),
+            speed: env::var("GM_TTS_SPEED").ok().and_then(|speed| speed.parse().ok()).unwrap_or(1.0),
+        }
+    }
+}
+
+/// The hosted ElevenLabs API.
+pub struct ElevenLabsTts {
+    client: Client,
+    api_key: String,
+    voice_id: String,
+    speed: f32,
+}
+
+impl ElevenLabsTts {
+    pub fn new(api_key: String, settings: VoiceSettings) -> Self {
+        Self {
+            client: Client::new(),
+            api_key,
+            voice_id: settings.voice.unwrap_or_else(|| "ZiJr5cZOXQztQsR7bLrz".to_string()),
+            speed: settings.speed,
+        }
+    }
+
+    pub fn from_env(settings: VoiceSettings) -> Self {
+        Self::new(env::var("ELEVEN_LABS_API_KEY").expect("ELEVEN_LABS_API_KEY must be set"), settings)
+    }
+
+    fn body(&self, text: &str) -> Value {
+        json!({
+            "text": text,
+            "model_id": "eleven_multilingual_v2",
+            "voice_settings": {
+                "stability": 0.5,
+                "similarity_boost": 0.5,
+                "speed": self.speed
+            }
+        })
+    }
+}
+
+impl TtsProvider for ElevenLabsTts {
+    fn name(&self) -> &str {
+        "elevenlabs"
+    }
+
+    fn voice(&self) -> String {
+        format!("{}@{}", self.voice_id, self.speed)
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String> {
+        let request = self.client
+            .post(format!("https://api.elevenlabs.io/v1/text-to-speech/{}", self.voice_id))
+            .header("xi-api-key", &self.api_key)
+            .json(&self.body(text));
+        download(request).map(Some)
+    }
+}
+
+/// Any endpoint that implements the OpenAI speech API.
+pub struct OpenAiTts {
+    client: Client,
+    url: String,
+    api_key: Option<String>,
+    model: String,
+    voice: String,
+    speed: f32,
+}
+
+impl OpenAiTts {
+    pub fn new(base_url: &str, api_key: Option<String>, model: String, settings: VoiceSettings) -> Self {
+        Self {
+            client: Client::new(),
+            url: format!("{}/audio/speech", base_url.trim_end_matches('/')),
+            api_key,
+            model,
+            voice: settings.voice.unwrap_or_else(|| "alloy".to_string()),
+            speed: settings.speed,
+        }
+    }
+
+    pub fn from_env(settings: VoiceSettings) -> Self {
+        let base_url = env::var("GM_TTS_BASE_URL").unwrap_or_else(|_| "https://api.openai.com/v1".to_string());
+        Self::new(
+            &base_url,
+            env::var("OPENAI_API_KEY").ok(),
+            env::var("GM_TTS_MODEL").unwrap_or_else(|_| "tts-1".to_string()),
+            settings,
+        )
+    }
+
+    fn body(&self, text: &str) -> Value {
+        json!({
+            "model": self.model,
+            "input": text,
+            "voice": self.voice,
+            "speed": self.speed,
+            "response_format": "mp3"
+        })
+    }
+}
+
+impl TtsProvider for OpenAiTts {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn voice(&self) -> String {
+        format!("{}/{}@{}", self.model, self.voice, self.speed)
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String> {
+        let mut request = self.client.post(&self.url).json(&self.body(text));
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        download(request).map(Some)
+    }
+}
+
+/// espeak-ng running locally, no network or API key needed.
+pub struct EspeakTts {
+    voice: String,
+    words_per_minute: u32,
+}
+
+impl EspeakTts {
+    pub fn new(settings: VoiceSettings) -> Self {
+        Self {
+            voice: settings.voice.unwrap_or_else(|| "en".to_string()),
+            // espeak-ng's own default is 175
+            words_per_minute: (175.0 * settings.speed).round() as u32,
+        }
+    }
+}
+
+impl TtsProvider for EspeakTts {
+    fn name(&self) -> &str {
+        "espeak"
+    }
+
+    fn voice(&self) -> String {
+        format!("{}@{}", self.voice, self.words_per_minute)
+    }
+
+    fn synthesize(&self, text: &str) -> Result<Option<Vec<u8>>, String> {
+        // The text goes in on stdin, so nothing in it is read as an argument
+        let mut espeak = Command::new("espeak-ng")
+            .args(["--stdout", "-v", &self.voice, "-s", &self.words_per_minute.to_string()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start espeak-ng: {}", e))?;
+        espeak.stdin.take().unwrap().write_all(text.as_bytes()).map_err(|e| e.to_string())?;
+
+        let output = espeak.wait_with_output().map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(format!("espeak-ng failed: {}", String::from_utf8_lossy(&output.stderr)));
+        }
+        Ok(Some(output.stdout))
+    }
+}
+
+/// Says nothing. For tests and for playing without sound.
+pub struct SilentTts;
+
+impl TtsProvider for SilentTts {
+    fn name(&self) -> &str {
+        "silent"
+    }
+
+    fn voice(&self) -> String {
+        String::new()
+    }
+
+    fn synthesize(&self, _text: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(None)
+    }
+}
+
+fn download(request: reqwest::blocking::RequestBuilder) -> Result<Vec<u8>, String> {
+    let response = request
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Speech request failed: {}", e))?;
+    let audio = response.bytes().map_err(|e| format!("Speech download failed: {}", e))?;
+    Ok(audio.to_vec())
+}
+
+/// Picks a provider from `GM_TTS` (`elevenlabs`, `openai`, `espeak` or `silent`).
+/// Without it, ElevenLabs is used if `ELEVEN_LABS_API_KEY` is set and narration is silent otherwise.
+/// Speech is served from the on-disk cache when it can be, see `SpeechCache::from_env`,
+/// and the second value says whether it is.
+pub fn tts_from_env() -> (Box<dyn TtsProvider>, bool) {
+    let settings = VoiceSettings::from_env();
+    let provider: Box<dyn TtsProvider> = match env::var("GM_TTS").as_deref() {
+        Ok("elevenlabs") => Box::new(ElevenLabsTts::from_env(settings)),
+        Ok("openai") => Box::new(OpenAiTts::from_env(settings)),
+        Ok("espeak") | Ok("local") => Box::new(EspeakTts::new(settings)),
+        Ok("silent") => Box::new(SilentTts),
+        Err(_) if env::var("ELEVEN_LABS_API_KEY").is_ok() => Box::new(ElevenLabsTts::from_env(settings)),
+        Err(_) => Box::new(SilentTts),
+        Ok(other) => panic!("Unknown GM_TTS: {}", other),
+    };
+    println!("Narration: {}", provider.name());
+
+    match SpeechCache::from_env() {
+        Some(cache) if provider.name() != "silent" => (Box::new(CachedTts::new(provider, cache)), true),
+        _ => (provider, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speech_bodies_carry_voice_and_escaped_text() {
+        let text = r#"Say "hi" & don't '$(rm -rf /)'"#;
+        let eleven_labs = ElevenLabsTts::new("key".to_string(), VoiceSettings { voice: None, speed: 1.5 });
+        let body: Value = serde_json::from_str(&eleven_labs.body(text).to_string()).unwrap();
+        assert_eq!(body["text"], text);
+        assert_eq!(body["voice_settings"]["speed"], 1.5);
+        assert_eq!(eleven_labs.voice(), "ZiJr5cZOXQztQsR7bLrz@1.5");
+
+        let settings = VoiceSettings { voice: Some("nova".to_string()), speed: 1.25 };
+        let openai = OpenAiTts::new("http://localhost:8880/v1/", None, "tts-1".to_string(), settings);
+        let body: Value = serde_json::from_str(&openai.body(text).to_string()).unwrap();
+        assert_eq!(body["input"], text);
+        assert_eq!(body["voice"], "nova");
+        assert_eq!(body["speed"], 1.25);
+        assert_eq!(openai.url, "http://localhost:8880/v1/audio/speech");
+
+        assert_eq!(EspeakTts::new(VoiceSettings { voice: None, speed: 2.0 }).words_per_minute, 350);
+        assert_eq!(SilentTts.synthesize(text), Ok(None));
+    }
+}
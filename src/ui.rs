@@ -1,7 +1,9 @@
-use crate::{Prompt};
+use crate::{MovementEvent, Prompt};
 use bevy::prelude::*;
 
 use bevy_egui::{egui, EguiContexts};
+use crate::audio_plugin::NarrationQueue;
+use crate::input::CustomActions;
 use crate::network::ChatInputRequest;
 
 #[derive(Default)]
@@ -9,7 +11,9 @@ pub struct UIPlugin;
 
 impl Plugin for UIPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, uniform_update_ui_system);
+        app.add_systems(Update, uniform_update_ui_system)
+            .add_systems(Update, custom_actions_ui_system)
+            .add_systems(Update, speaking_indicator_system);
     }
 }
 
@@ -36,7 +40,51 @@ fn uniform_update_ui_system(
 
     if clicked {
         event_writer.send(ChatInputRequest {
-            text: prompt.text.clone()
+            text: prompt.text.clone(),
+            turn: None,
         });
     }
-}
\ No newline at end of file
+}
+
+/// Lists the controls the game master declared, each one clickable
+fn custom_actions_ui_system(
+    mut ctx: EguiContexts,
+    custom_actions: Res<CustomActions>,
+    mut event_writer: EventWriter<MovementEvent>
+) {
+    if custom_actions.actions.is_empty() {
+        return;
+    }
+
+    egui::Window::new("Controls").show(ctx.ctx_mut(), |ui| {
+        for action in &custom_actions.actions {
+            ui.horizontal(|ui| {
+                if ui.button(&action.name).clicked() {
+                    event_writer.send(MovementEvent::Custom(action.name.clone()));
+                }
+                if let Some(key) = &action.key {
+                    ui.label(format!("[{}]", key));
+                }
+                if let Some(description) = &action.description {
+                    ui.label(description);
+                }
+            });
+        }
+    });
+}
+
+/// Shows what the narrator is saying while it says it
+fn speaking_indicator_system(mut ctx: EguiContexts, narration: Res<NarrationQueue>) {
+    let Some(line) = narration.speaking() else {
+        return;
+    };
+
+    egui::Area::new("speaking".into())
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(8.0, -8.0))
+        .show(ctx.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(line);
+            });
+        });
+}
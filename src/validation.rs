@@ -0,0 +1,177 @@
+use std::env;
+use bevy::prelude::*;
+use serde::Serialize;
+use crate::actions::{PointColor, PointHex};
+use crate::grid::{supported_size, GridState, MAX_GRID_SIZE};
+use crate::Point;
+
+/// What happens to a point that falls outside the board
+#[derive(Clone, Copy, PartialEq)]
+pub enum PointPolicy {
+    Reject,
+    /// Move it to the nearest cell on the board
+    Clamp,
+}
+
+/// How points from the game master are checked before they reach the board
+#[derive(Resource)]
+pub struct PointValidation {
+    pub policy: PointPolicy,
+    /// Tell the model about its mistakes on the next turn
+    pub report: bool,
+}
+
+impl Default for PointValidation {
+    fn default() -> Self {
+        Self {
+            policy: match env::var("GM_POINT_POLICY").as_deref() {
+                Ok("clamp") => PointPolicy::Clamp,
+                _ => PointPolicy::Reject,
+            },
+            report: env::var("GM_REPORT_VIOLATIONS").map_or(true, |report| report != "0" && report != "false"),
+        }
+    }
+}
+
+impl PointValidation {
+    pub fn validator(&self, grid: &GridState) -> PointValidator {
+        PointValidator { width: grid.width, height: grid.height, policy: self.policy }
+    }
+}
+
+/// A point or board size the game master got wrong, and what was done about it
+#[derive(Serialize)]
+pub struct Violation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point: Option<Point>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hex: Option<String>,
+    pub problem: String,
+    pub resolution: String,
+}
+
+/// Violations waiting to be sent back with the next user turn
+#[derive(Resource, Default)]
+pub struct PendingViolations(pub Vec<Violation>);
+
+/// Checks points against a board size, which can run ahead of the board when a response resizes it
+#[derive(Clone, Copy)]
+pub struct PointValidator {
+    width: u8,
+    height: u8,
+    policy: PointPolicy,
+}
+
+impl PointValidator {
+    /// Checks the points that follow against the new size, before the board itself is resized.
+    /// Sizes the board can't take are clamped the way `GridState::new` clamps them, and reported.
+    pub fn resize(&mut self, width: u8, height: u8) -> Option<Violation> {
+        (self.width, self.height) = supported_size(width, height);
+        if (self.width, self.height) == (width, height) {
+            return None;
+        }
+        println!("Invalid grid size {}x{}, using {}x{}", width, height, self.width, self.height);
+        Some(Violation {
+            point: None,
+            hex: None,
+            problem: format!("{}x{} is not a board size, width and height go from 1 to {}", width, height, MAX_GRID_SIZE),
+            resolution: format!("resized to {}x{}", self.width, self.height),
+        })
+    }
+
+    pub fn size(&self) -> (u8, u8) {
+        (self.width, self.height)
+    }
+
+    /// Keeps the points that can be drawn, and describes what was wrong with the rest
+    pub fn validate(&self, points: &[PointHex]) -> (Vec<PointColor>, Vec<Violation>) {
+        let mut valid = vec![];
+        let mut violations = vec![];
+
+        for point_hex in points {
+            let Ok(color) = Color::hex(&point_hex.hex) else {
+                violations.push(Violation {
+                    point: Some(point_hex.point.clone()),
+                    hex: Some(point_hex.hex.clone()),
+                    problem: "hex is not a valid color".to_string(),
+                    resolution: "rejected".to_string(),
+                });
+                continue;
+            };
+
+            let point = &point_hex.point;
+            if point.x < self.width && point.y < self.height {
+                valid.push(PointColor { color, point: point.clone() });
+                continue;
+            }
+
+            let problem = format!(
+                "point is outside the {}x{} board, x must be below {} and y below {}",
+                self.width, self.height, self.width, self.height
+            );
+            let resolution = match self.policy {
+                PointPolicy::Reject => "rejected".to_string(),
+                PointPolicy::Clamp => {
+                    let clamped = Point {
+                        x: point.x.min(self.width - 1),
+                        y: point.y.min(self.height - 1),
+                    };
+                    let resolution = format!("clamped to ({}, {})", clamped.x, clamped.y);
+                    valid.push(PointColor { color, point: clamped });
+                    resolution
+                }
+            };
+            violations.push(Violation {
+                point: Some(point.clone()),
+                hex: Some(point_hex.hex.clone()),
+                problem,
+                resolution,
+            });
+        }
+
+        for violation in &violations {
+            let (Some(point), Some(hex)) = (&violation.point, &violation.hex) else {
+                continue;
+            };
+            println!(
+                "Invalid point ({}, {}) {}: {}, {}",
+                point.x, point.y, hex, violation.problem, violation.resolution
+            );
+        }
+        (valid, violations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point_hex(x: u8, y: u8, hex: &str) -> PointHex {
+        serde_json::from_value(serde_json::json!({ "hex": hex, "point": { "x": x, "y": y } })).unwrap()
+    }
+
+    #[test]
+    fn test_validate_rejects_or_clamps_bad_points() {
+        let grid = GridState::new(10, 5);
+        let points = [point_hex(1, 1, "#ff0000"), point_hex(12, 3, "#00ff00"), point_hex(2, 2, "nope")];
+
+        let reject = PointValidation { policy: PointPolicy::Reject, report: true };
+        let (valid, violations) = reject.validator(&grid).validate(&points);
+        assert_eq!(valid.len(), 1);
+        assert_eq!(violations.len(), 2);
+
+        let clamp = PointValidation { policy: PointPolicy::Clamp, report: true };
+        let (valid, violations) = clamp.validator(&grid).validate(&points);
+        assert_eq!(valid.len(), 2);
+        assert!(valid[1].point == Point { x: 9, y: 3 });
+        assert_eq!(violations[0].resolution, "clamped to (9, 3)");
+
+        let mut resized = reject.validator(&grid);
+        assert!(resized.resize(20, 5).is_none());
+        assert_eq!(resized.validate(&points).0.len(), 2);
+
+        let mut clamped = clamp.validator(&grid);
+        assert_eq!(clamped.resize(0, 200).unwrap().resolution, "resized to 1x64");
+        assert!(clamped.validate(&points).0[1].point == Point { x: 0, y: 3 });
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::{env, thread};
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{self, FromSample, SampleFormat, SizedSample};
+use rodio::{Decoder, Source};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters, WhisperState};
+use crate::input::InputBindings;
+use crate::network::ChatInputRequest;
+use crate::EguiWantsFocus;
+
+/// Whisper only understands 16kHz mono
+const WHISPER_SAMPLE_RATE: u32 = 16_000;
+
+/// Push-to-talk voice commands, transcribed locally with the whisper model at `GM_WHISPER_MODEL`
+/// and sent to the game master like anything typed into the prompt.
+pub struct VoicePlugin;
+
+impl Plugin for VoicePlugin {
+    fn build(&self, app: &mut App) {
+        let Ok(model_path) = env::var("GM_WHISPER_MODEL") else {
+            return;
+        };
+        // A WAV file can stand in for the microphone, e.g. to try a command without speaking it
+        let input = match env::var("GM_VOICE_WAV") {
+            Ok(path) => AudioInput::Wav(path.into()),
+            Err(_) => AudioInput::Microphone,
+        };
+
+        app.insert_resource(VoiceInput::spawn(model_path, input))
+            .add_systems(Update, push_to_talk.run_if(resource_equals(EguiWantsFocus(false))))
+            .add_systems(Update, read_transcripts);
+    }
+}
+
+enum VoiceCommand {
+    StartRecording,
+    StopRecording,
+}
+
+/// Records and transcribes on its own thread, so whisper never holds up a frame
+#[derive(Resource)]
+pub struct VoiceInput {
+    commands: Sender<VoiceCommand>,
+    transcripts: Receiver<Result<String, String>>,
+}
+
+impl VoiceInput {
+    pub fn spawn(model_path: String, input: AudioInput) -> Self {
+        let (commands, command_receiver) = crossbeam_channel::unbounded();
+        let (transcript_sender, transcripts) = crossbeam_channel::unbounded();
+
+        thread::spawn(move || {
+            let context = match WhisperContext::new_with_params(&model_path, WhisperContextParameters::default()) {
+                Ok(context) => context,
+                Err(e) => {
+                    println!("Failed to load whisper model {}: {}", model_path, e);
+                    return;
+                }
+            };
+            let mut state = context.create_state().expect("Failed to create whisper state");
+
+            let mut recording = None;
+            for command in command_receiver.iter() {
+                match command {
+                    VoiceCommand::StartRecording => match input.record() {
+                        Ok(started) => recording = Some(started),
+                        Err(e) => {
+                            let _ = transcript_sender.send(Err(e));
+                        }
+                    },
+                    VoiceCommand::StopRecording => {
+                        let Some(finished) = recording.take() else {
+                            continue;
+                        };
+                        let audio = to_whisper_input(finished.finish());
+                        let _ = transcript_sender.send(transcribe(&mut state, &audio));
+                    }
+                }
+            }
+        });
+
+        Self { commands, transcripts }
+    }
+}
+
+/// Where push-to-talk audio comes from
+pub enum AudioInput {
+    /// The default input device
+    Microphone,
+    /// A recording played back in place of the microphone
+    Wav(PathBuf),
+}
+
+/// Audio as it was captured, interleaved if there is more than one channel
+pub struct Captured {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+}
+
+/// A recording in progress. The microphone stops when it is finished or dropped.
+pub struct Recording {
+    samples: Arc<Mutex<Vec<f32>>>,
+    sample_rate: u32,
+    channels: u16,
+    _stream: Option<cpal::Stream>,
+}
+
+impl Recording {
+    pub fn finish(self) -> Captured {
+        let samples = std::mem::take(&mut *self.samples.lock().unwrap());
+        Captured { samples, sample_rate: self.sample_rate, channels: self.channels }
+    }
+}
+
+impl AudioInput {
+    pub fn record(&self) -> Result<Recording, String> {
+        match self {
+            AudioInput::Microphone => record_microphone(),
+            AudioInput::Wav(path) => {
+                let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+                let decoder = Decoder::new_wav(BufReader::new(file))
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let (sample_rate, channels) = (decoder.sample_rate(), decoder.channels());
+                Ok(Recording {
+                    samples: Arc::new(Mutex::new(decoder.convert_samples().collect())),
+                    sample_rate,
+                    channels,
+                    _stream: None,
+                })
+            }
+        }
+    }
+}
+
+fn record_microphone() -> Result<Recording, String> {
+    let device = cpal::default_host().default_input_device().ok_or("No microphone found")?;
+    let config = device.default_input_config().map_err(|e| e.to_string())?;
+    let samples = Arc::new(Mutex::new(Vec::new()));
+
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => input_stream::<f32>(&device, &config.config(), samples.clone()),
+        SampleFormat::I16 => input_stream::<i16>(&device, &config.config(), samples.clone()),
+        SampleFormat::U16 => input_stream::<u16>(&device, &config.config(), samples.clone()),
+        format => return Err(format!("Unsupported microphone sample format {}", format)),
+    }?;
+    stream.play().map_err(|e| e.to_string())?;
+
+    Ok(Recording {
+        samples,
+        sample_rate: config.sample_rate().0,
+        channels: config.channels(),
+        _stream: Some(stream),
+    })
+}
+
+fn input_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, samples: Arc<Mutex<Vec<f32>>>) -> Result<cpal::Stream, String>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    device.build_input_stream(
+        config,
+        move |data: &[T], _| samples.lock().unwrap().extend(data.iter().map(|sample| sample.to_sample::<f32>())),
+        |e| println!("Microphone error: {}", e),
+        None,
+    ).map_err(|e| e.to_string())
+}
+
+/// Mixes down to mono and resamples to 16kHz
+fn to_whisper_input(captured: Captured) -> Vec<f32> {
+    let channels = captured.channels.max(1) as usize;
+    let mono: Vec<f32> = captured.samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+    if mono.is_empty() || captured.sample_rate == WHISPER_SAMPLE_RATE {
+        return mono;
+    }
+
+    let step = captured.sample_rate as f64 / WHISPER_SAMPLE_RATE as f64;
+    let length = (mono.len() as f64 / step) as usize;
+    (0..length).map(|i| {
+        let position = i as f64 * step;
+        let index = position as usize;
+        let next = mono[(index + 1).min(mono.len() - 1)];
+        let fraction = (position - index as f64) as f32;
+        mono[index] + (next - mono[index]) * fraction
+    }).collect()
+}
+
+fn transcribe(state: &mut WhisperState, audio: &[f32]) -> Result<String, String> {
+    let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    params.set_language(Some("en"));
+    params.set_print_progress(false);
+    params.set_print_realtime(false);
+    params.set_print_special(false);
+    params.set_print_timestamps(false);
+
+    state.full(params, audio).map_err(|e| e.to_string())?;
+    let segments = state.full_n_segments().map_err(|e| e.to_string())?;
+    let mut text = String::new();
+    for segment in 0..segments {
+        text.push_str(&state.full_get_segment_text(segment).map_err(|e| e.to_string())?);
+    }
+    Ok(text.trim().to_string())
+}
+
+fn push_to_talk(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    bindings: Res<InputBindings>,
+    voice: Res<VoiceInput>,
+) {
+    if keyboard.just_pressed(bindings.push_to_talk) {
+        println!("Listening...");
+        let _ = voice.commands.send(VoiceCommand::StartRecording);
+    }
+    if keyboard.just_released(bindings.push_to_talk) {
+        let _ = voice.commands.send(VoiceCommand::StopRecording);
+    }
+}
+
+fn read_transcripts(voice: Res<VoiceInput>, mut event_writer: EventWriter<ChatInputRequest>) {
+    for transcript in voice.transcripts.try_iter() {
+        match transcript {
+            Ok(text) if text.is_empty() => println!("Didn't catch that"),
+            Ok(text) => {
+                println!("Heard: {}", text);
+                event_writer.send(ChatInputRequest { text, turn: None });
+            }
+            Err(e) => println!("Voice input failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_input_is_mixed_and_resampled_for_whisper() {
+        let path = env::temp_dir().join("gm_voice_test.wav");
+        let spec = hound::WavSpec { channels: 2, sample_rate: 44_100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..44_100 {
+            writer.write_sample(i16::MAX / 2).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let audio = to_whisper_input(AudioInput::Wav(path.clone()).record().unwrap().finish());
+        let _ = std::fs::remove_file(path);
+        assert_eq!(audio.len(), WHISPER_SAMPLE_RATE as usize);
+        assert!(audio.iter().all(|sample| (sample - 0.25).abs() < 0.01));
+    }
+}